@@ -0,0 +1,357 @@
+use std::{
+    fs::{self, create_dir_all, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail, Context};
+use flate2::read::GzDecoder;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::{
+    report::{Event, Reporter},
+    Directories, Result,
+};
+
+impl Directories {
+    /// Returns the unpacked contents of the archive at `url`, downloading it if it's not cached.
+    ///
+    /// The cache is content-addressed, so archives are only unpacked once per `sha256`.
+    pub(crate) fn archive(
+        &self,
+        url: &str,
+        sha256: &str,
+        reporter: &mut dyn Reporter,
+    ) -> Result<PathBuf> {
+        let (dir, _) = self.artifact(url, &[], None, Some(sha256), reporter)?;
+
+        Ok(dir)
+    }
+
+    /// Returns the cached, unpacked artifact with the given `sha256`, if any
+    pub(crate) fn cached_artifact(&self, sha256: &str) -> Result<Option<PathBuf>> {
+        let sha256 = sha256.to_ascii_lowercase();
+        if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("invalid sha256 {}", sha256)
+        }
+
+        let dir = self.global_artifacts.join("sha256").join(&sha256);
+
+        Ok(Some(dir).filter(|d| d.exists()))
+    }
+
+    /// Downloads and unpacks the artifact at `url`, unless the expected `sha256` is cached already.
+    ///
+    /// Files that aren't archives are placed into the directory as `file_name`,
+    /// which defaults to the last segment of the url.
+    /// Returns the unpacked directory and the actual checksum.
+    pub(crate) fn artifact(
+        &self,
+        url: &str,
+        headers: &[(&str, String)],
+        file_name: Option<&str>,
+        sha256: Option<&str>,
+        reporter: &mut dyn Reporter,
+    ) -> Result<(PathBuf, String)> {
+        if let Some(sha256) = sha256 {
+            if let Some(dir) = self.cached_artifact(sha256)? {
+                return Ok((dir, sha256.to_ascii_lowercase()));
+            }
+        }
+
+        let downloads_dir = self.global_artifacts.join("downloads");
+        create_dir_all(&downloads_dir).with_context(|| {
+            anyhow!("failed to create downloads dir {}", downloads_dir.display())
+        })?;
+        let download_path = downloads_dir.join(format!("{}.part", &sha256_str(url)[..16]));
+
+        reporter.report(Event::Downloading {
+            url: url.to_string(),
+        });
+        download(url, headers, &download_path)?;
+
+        let actual_sha256 = sha256_file(&download_path)?;
+        if let Some(sha256) = sha256 {
+            if actual_sha256 != sha256.to_ascii_lowercase() {
+                fs::remove_file(&download_path).context("could not remove download")?;
+                bail!(
+                    "checksum mismatch for {}: expected sha256 {}, got {}",
+                    url,
+                    sha256,
+                    actual_sha256
+                )
+            }
+        }
+
+        let unpacked_dir = self.global_artifacts.join("sha256").join(&actual_sha256);
+        if !unpacked_dir.exists() {
+            let tmp_dir = unpacked_dir.with_extension("tmp");
+            if tmp_dir.exists() {
+                remove_dir_all::remove_dir_all(&tmp_dir)
+                    .context("failed to remove leftover unpacked files")?;
+            }
+            let file_name = file_name
+                .map(ToString::to_string)
+                .or_else(|| {
+                    let url = Url::from_str(url).ok()?;
+                    url.path_segments()?.next_back().map(ToString::to_string)
+                })
+                .filter(|n| !n.is_empty() && !n.contains(['/', '\\']) && n != "..")
+                .unwrap_or_else(|| "artifact".to_string());
+            unpack(&download_path, &file_name, &tmp_dir)
+                .with_context(|| anyhow!("failed to unpack artifact from {}", url))?;
+            move_into_cache(&tmp_dir, &unpacked_dir)?;
+        }
+        fs::remove_file(&download_path).context("could not remove download")?;
+
+        Ok((unpacked_dir, actual_sha256))
+    }
+}
+
+impl Directories {
+    /// Returns the cached directory `key` was downloaded into by `download`, unless the
+    /// expected `sha256` is cached already.
+    ///
+    /// The checksum is computed over the relative paths and contents of the files.
+    /// Returns the cached directory and the actual checksum.
+    pub(crate) fn artifact_dir(
+        &self,
+        key: &str,
+        sha256: Option<&str>,
+        download: impl FnOnce(&Path) -> Result<()>,
+    ) -> Result<(PathBuf, String)> {
+        if let Some(sha256) = sha256 {
+            if let Some(dir) = self.cached_artifact(sha256)? {
+                return Ok((dir, sha256.to_ascii_lowercase()));
+            }
+        }
+
+        let downloads_dir = self.global_artifacts.join("downloads");
+        create_dir_all(&downloads_dir).with_context(|| {
+            anyhow!("failed to create downloads dir {}", downloads_dir.display())
+        })?;
+        let download_dir = downloads_dir.join(format!("{}.part", &sha256_str(key)[..16]));
+        if download_dir.exists() {
+            remove_dir_all::remove_dir_all(&download_dir)
+                .context("failed to remove leftover downloaded files")?;
+        }
+
+        download(&download_dir)?;
+
+        let actual_sha256 = sha256_dir(&download_dir)?;
+        if let Some(sha256) = sha256 {
+            if actual_sha256 != sha256.to_ascii_lowercase() {
+                remove_dir_all::remove_dir_all(&download_dir)
+                    .context("could not remove download")?;
+                bail!(
+                    "checksum mismatch for {}: expected sha256 {}, got {}",
+                    key,
+                    sha256,
+                    actual_sha256
+                )
+            }
+        }
+
+        let dir = self.global_artifacts.join("sha256").join(&actual_sha256);
+        if dir.exists() {
+            remove_dir_all::remove_dir_all(&download_dir).context("could not remove download")?;
+        } else {
+            create_dir_all(dir.parent().expect("artifacts are in a directory"))
+                .context("could not create artifact dir")?;
+            move_into_cache(&download_dir, &dir)?;
+        }
+
+        Ok((dir, actual_sha256))
+    }
+}
+
+/// Moves the directory `from` to `to`, unless another process cached `to` in the meantime
+fn move_into_cache(from: &Path, to: &Path) -> Result<()> {
+    if let Err(e) = fs::rename(from, to) {
+        if !to.exists() {
+            return Err(e).context("could not move artifact into the cache");
+        }
+        remove_dir_all::remove_dir_all(from).context("could not remove duplicate artifact")?;
+    }
+
+    Ok(())
+}
+
+/// Downloads `url` (`http(s)://` or `file://`) into the file `dest`
+fn download(url: &str, headers: &[(&str, String)], dest: &Path) -> Result<()> {
+    let parsed = Url::from_str(url).context("could not parse url")?;
+    let mut file =
+        File::create(dest).with_context(|| anyhow!("could not create file {}", dest.display()))?;
+
+    if parsed.scheme() == "file" {
+        let path = parsed
+            .to_file_path()
+            .map_err(|_| anyhow!("invalid file url {}", url))?;
+        let mut source =
+            File::open(&path).with_context(|| anyhow!("could not open {}", path.display()))?;
+        io::copy(&mut source, &mut file)
+    } else {
+        let response = headers
+            .iter()
+            .fold(ureq::get(url), |r, (name, value)| r.set(name, value))
+            .call()
+            .with_context(|| anyhow!("failed to download {}", url))?;
+        io::copy(&mut response.into_reader(), &mut file)
+    }
+    .with_context(|| anyhow!("failed to download {}", url))?;
+
+    Ok(())
+}
+
+fn sha256_str(s: &str) -> String {
+    hex::encode(Sha256::digest(s.as_bytes()))
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut file =
+        File::open(path).with_context(|| anyhow!("could not open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).context("could not hash file")?;
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Hashes the relative paths and contents of the files in `dir`, in sorted order
+fn sha256_dir(dir: &Path) -> Result<String> {
+    fn visit(root: &Path, dir: &Path, hasher: &mut Sha256) -> Result<()> {
+        let mut entries = fs::read_dir(dir)
+            .and_then(|entries| {
+                entries
+                    .map(|e| e.map(|e| e.path()))
+                    .collect::<io::Result<Vec<_>>>()
+            })
+            .with_context(|| anyhow!("could not list {}", dir.display()))?;
+        entries.sort();
+
+        for path in entries {
+            if path.is_dir() {
+                visit(root, &path, hasher)?;
+            } else {
+                let relative = path.strip_prefix(root).expect("entries are inside of root");
+                let relative = relative.to_string_lossy().replace('\\', "/");
+                hasher.update(relative.as_bytes());
+                hasher.update([0]);
+                hasher.update(sha256_file(&path)?.as_bytes());
+                hasher.update([b'\n']);
+            }
+        }
+
+        Ok(())
+    }
+
+    let mut hasher = Sha256::new();
+    visit(dir, dir, &mut hasher)?;
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Unpacks a `.tar.gz`, `.tar` or `.zip` archive, detecting the format by its contents.
+///
+/// Other files are copied into `dest` as `file_name`.
+fn unpack(archive: &Path, file_name: &str, dest: &Path) -> Result<()> {
+    let mut magic = [0u8; 262];
+    let len = File::open(archive)
+        .and_then(|mut f| f.read(&mut magic))
+        .context("could not read archive")?;
+    let magic = &magic[..len];
+
+    let file = File::open(archive).context("could not open archive")?;
+    if magic.starts_with(&[0x1f, 0x8b]) {
+        tar::Archive::new(GzDecoder::new(file))
+            .unpack(dest)
+            .context("invalid tar.gz archive")
+    } else if magic.starts_with(b"PK\x03\x04") {
+        zip::ZipArchive::new(file)
+            .and_then(|mut z| z.extract(dest))
+            .context("invalid zip archive")
+    } else if magic.get(257..262) == Some(b"ustar") {
+        tar::Archive::new(file)
+            .unpack(dest)
+            .context("invalid tar archive")
+    } else {
+        create_dir_all(dest).context("could not create artifact dir")?;
+        fs::copy(archive, dest.join(file_name))
+            .map(|_| ())
+            .context("could not copy artifact")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use flate2::{write::GzEncoder, Compression};
+    use url::Url;
+
+    use crate::{artifact::sha256_file, test_util::directories};
+
+    fn tar_gz(root: &Path) -> String {
+        let src = root.join("src").join("pkg-1.0");
+        fs::create_dir_all(src.join("include")).unwrap();
+        fs::write(src.join("include").join("pkg.h"), "// header").unwrap();
+
+        let archive = root.join("pkg-1.0.tar.gz");
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            fs::File::create(&archive).unwrap(),
+            Compression::default(),
+        ));
+        builder.append_dir_all("pkg-1.0", &src).unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        Url::from_file_path(&archive).unwrap().to_string()
+    }
+
+    #[test]
+    fn archive_from_file_url() {
+        let root = tempfile::tempdir().unwrap();
+        let url = tar_gz(root.path());
+        let sha256 = sha256_file(&Url::parse(&url).unwrap().to_file_path().unwrap()).unwrap();
+
+        let dirs = directories(root.path(), "project");
+        let unpacked = dirs.archive(&url, &sha256, &mut |_| {}).unwrap();
+        assert!(unpacked.join("pkg-1.0/include/pkg.h").is_file());
+        assert_eq!(
+            dirs.archive(&url, &sha256.to_uppercase(), &mut |_| {})
+                .unwrap(),
+            unpacked
+        );
+
+        let wrong = "0".repeat(64);
+        let err = dirs.archive(&url, &wrong, &mut |_| {}).unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"));
+        assert!(!dirs.global_artifacts.join("sha256").join(&wrong).exists());
+    }
+
+    #[test]
+    fn downloaded_dirs() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = directories(root.path(), "project");
+        let download = |dest: &Path| {
+            fs::create_dir_all(dest.join("bin")).unwrap();
+            fs::write(dest.join("bin/tool"), "tool").unwrap();
+            Ok(())
+        };
+
+        let (dir, sha256) = dirs.artifact_dir("tool 1.0.0", None, download).unwrap();
+        assert!(dir.join("bin/tool").is_file());
+        assert_eq!(dir, dirs.global_artifacts.join("sha256").join(&sha256));
+
+        let (cached, _) = dirs
+            .artifact_dir("tool 1.0.0", Some(&sha256), |_| panic!("not cached"))
+            .unwrap();
+        assert_eq!(cached, dir);
+
+        let wrong = "0".repeat(64);
+        let err = dirs
+            .artifact_dir("tool 1.0.0", Some(&wrong), download)
+            .unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"));
+    }
+}
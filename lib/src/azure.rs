@@ -0,0 +1,268 @@
+use std::{env, path::Path, process::Command};
+
+use anyhow::{anyhow, bail, Context};
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+
+use crate::Result;
+
+pub(crate) const DEFAULT_FEEDS_URL: &str = "https://feeds.dev.azure.com";
+pub(crate) const DEFAULT_ORGANIZATION_URL: &str = "https://dev.azure.com";
+/// Environment variable for a base url replacing both of the above, e.g. for a local stub
+pub(crate) const API_URL_ENV: &str = "AZURE_DEVOPS_API_URL";
+/// Environment variable for the personal access token, as used by the `az devops` CLI
+pub(crate) const PAT_ENV: &str = "AZURE_DEVOPS_EXT_PAT";
+/// Environment variable for the `az` executable, e.g. for a stub
+pub(crate) const AZ_CLI_ENV: &str = "PKGSTRAP_AZ_CLI";
+
+const API_VERSION: &str = "7.1-preview.1";
+
+/// Location of a universal package
+#[derive(Clone, Debug)]
+pub(crate) struct Package<'a> {
+    /// Base url, replacing both the feeds and organization hosts if set
+    pub api_url: Option<&'a str>,
+    pub organization: &'a str,
+    pub project: Option<&'a str>,
+    pub feed: &'a str,
+    pub name: &'a str,
+    /// The `az` executable, defaults to `$PKGSTRAP_AZ_CLI` or `az`
+    pub az_cli: Option<&'a str>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Packages {
+    value: Vec<PackageInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PackageInfo {
+    name: String,
+    versions: Vec<PackageVersion>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PackageVersion {
+    version: String,
+    #[serde(default)]
+    is_deleted: bool,
+}
+
+impl<'a> Package<'a> {
+    fn base_url(&self, default: &str) -> String {
+        let base = self
+            .api_url
+            .map(ToString::to_string)
+            .or_else(|| env::var(API_URL_ENV).ok())
+            .unwrap_or_else(|| default.to_string());
+        let base = base.trim_end_matches('/');
+
+        match self.project {
+            Some(project) => format!("{}/{}/{}", base, self.organization, project),
+            None => format!("{}/{}", base, self.organization),
+        }
+    }
+
+    /// Downloads the files of `version` into the directory `dest`.
+    ///
+    /// Universal packages are stored deduplicated and have no REST endpoint for their contents,
+    /// so this uses `az artifacts universal download`, which needs the `azure-devops` extension
+    /// and authenticates with `$AZURE_DEVOPS_EXT_PAT`.
+    pub fn download(&self, version: &str, dest: &Path) -> Result<()> {
+        let organization = self
+            .api_url
+            .map(ToString::to_string)
+            .or_else(|| env::var(API_URL_ENV).ok())
+            .unwrap_or_else(|| DEFAULT_ORGANIZATION_URL.to_string());
+        let az = self
+            .az_cli
+            .map(ToString::to_string)
+            .or_else(|| env::var(AZ_CLI_ENV).ok())
+            .unwrap_or_else(|| "az".to_string());
+
+        let mut command = Command::new(&az);
+        command
+            .args(["artifacts", "universal", "download", "--only-show-errors"])
+            .arg("--organization")
+            .arg(format!(
+                "{}/{}",
+                organization.trim_end_matches('/'),
+                self.organization
+            ))
+            .args([
+                "--feed",
+                self.feed,
+                "--name",
+                self.name,
+                "--version",
+                version,
+            ])
+            .arg("--path")
+            .arg(dest);
+        match self.project {
+            Some(project) => command.args(["--scope", "project", "--project", project]),
+            None => command.args(["--scope", "organization"]),
+        };
+
+        let output = command.output().with_context(|| {
+            anyhow!(
+                "could not run {}, which is required for Azure Artifacts",
+                az
+            )
+        })?;
+        if !output.status.success() {
+            bail!(
+                "{} failed ({}): {}",
+                az,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Resolves `version` (an exact version or a version requirement like `^1.2`)
+    /// to the highest matching version in the feed
+    pub fn resolve_version(&self, version: &str) -> Result<String> {
+        if Version::parse(version).is_ok() {
+            return Ok(version.to_string());
+        }
+        let req = VersionReq::parse(version)
+            .with_context(|| anyhow!("invalid version requirement {}", version))?;
+
+        let url = format!(
+            "{}/_apis/packaging/feeds/{}/packages?protocolType=upack&packageNameQuery={}&includeAllVersions=true&api-version={}",
+            self.base_url(DEFAULT_FEEDS_URL),
+            self.feed,
+            self.name,
+            API_VERSION
+        );
+        let request = headers()
+            .iter()
+            .fold(ureq::get(&url), |r, (name, value)| r.set(name, value));
+        let packages: Packages = request
+            .call()
+            .with_context(|| anyhow!("failed to query versions of {}", self.name))?
+            .into_json()
+            .context("invalid package list response")?;
+
+        packages
+            .value
+            .into_iter()
+            .filter(|p| p.name.eq_ignore_ascii_case(self.name))
+            .flat_map(|p| p.versions)
+            .filter(|v| !v.is_deleted)
+            .filter_map(|v| Version::parse(&v.version).ok())
+            .filter(|v| req.matches(v))
+            .max()
+            .map(|v| v.to_string())
+            .with_context(|| anyhow!("no version of {} matches {}", self.name, version))
+    }
+}
+
+pub(crate) fn headers() -> Vec<(&'static str, String)> {
+    match env::var(PAT_ENV) {
+        Ok(pat) => vec![(
+            "Authorization",
+            format!("Basic {}", base64::encode(format!(":{}", pat))),
+        )],
+        Err(_) => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread};
+
+    use tiny_http::{Response, Server};
+
+    use crate::azure::Package;
+
+    fn mock_feed() -> String {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let api_url = format!("http://{}", server.server_addr());
+
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let response = if request
+                    .url()
+                    .starts_with("/org/_apis/packaging/feeds/feed/packages?")
+                {
+                    Response::from_string(
+                        r#"{"count": 1, "value": [{"name": "tool", "versions": [
+                            {"version": "1.2.0"},
+                            {"version": "1.4.1"},
+                            {"version": "1.5.0", "isDeleted": true},
+                            {"version": "2.0.0"}
+                        ]}]}"#,
+                    )
+                } else {
+                    Response::from_string("not found").with_status_code(404)
+                };
+                request.respond(response).unwrap();
+            }
+        });
+
+        api_url
+    }
+
+    #[test]
+    fn resolve_versions() {
+        let api_url = mock_feed();
+        let package = Package {
+            api_url: Some(&api_url),
+            organization: "org",
+            project: None,
+            feed: "feed",
+            name: "tool",
+            az_cli: None,
+        };
+
+        assert_eq!(package.resolve_version("^1.2").unwrap(), "1.4.1");
+        assert_eq!(package.resolve_version("*").unwrap(), "2.0.0");
+        assert_eq!(package.resolve_version("1.0.0").unwrap(), "1.0.0");
+        assert!(package.resolve_version("^3").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn download_with_az() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // records its arguments and writes a file into the directory after `--path`
+        let dir = tempfile::tempdir().unwrap();
+        let az = dir.path().join("az");
+        fs::write(
+            &az,
+            format!(
+                "#!/bin/sh\necho \"$@\" > {}/args\nwhile [ \"$1\" != --path ]; do shift; done\nmkdir -p \"$2\" && echo tool > \"$2/tool.txt\"\n",
+                dir.path().display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&az, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let package = Package {
+            api_url: Some("https://azure.example.com/"),
+            organization: "org",
+            project: Some("project"),
+            feed: "feed",
+            name: "tool",
+            az_cli: az.to_str(),
+        };
+        let dest = dir.path().join("dest");
+        package.download("1.4.1", &dest).unwrap();
+
+        assert_eq!(fs::read_to_string(dest.join("tool.txt")).unwrap(), "tool\n");
+        assert_eq!(
+            fs::read_to_string(dir.path().join("args")).unwrap().trim(),
+            format!(
+                "artifacts universal download --only-show-errors --organization https://azure.example.com/org --feed feed --name tool --version 1.4.1 --path {} --scope project --project project",
+                dest.display()
+            )
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context};
+use git2::Repository;
+use serde::Serialize;
+
+use crate::{
+    report::{Event, Reporter},
+    resolved::prune_dangling_worktrees,
+    Directories, Result,
+};
+
+/// Config keys of the usage index, which lives in the config of each bare repo so that
+/// parallel jobs never write the same file
+const LAST_USED_KEY: &str = "pkgstrap.lastused";
+const URL_KEY: &str = "pkgstrap.url";
+
+/// A bare repository in the global git cache
+#[derive(Clone, Debug, Serialize)]
+pub struct CachedRepo {
+    pub dir: PathBuf,
+    /// URL the repo was last opened with
+    pub url: Option<String>,
+    /// Size on disk in bytes
+    pub size: u64,
+    /// Seconds since the Unix epoch, falling back to the last fetch for repos
+    /// cloned before usage was recorded
+    pub last_used: Option<u64>,
+    /// Worktrees whose directories still exist
+    pub worktrees: Vec<PathBuf>,
+}
+
+/// Records that the global bare repository `repo` was opened for `url`
+pub(crate) fn record_usage(repo: &Repository, url: &str) -> Result<()> {
+    let mut config = repo.config().context("could not open repo config")?;
+    config
+        .set_i64(LAST_USED_KEY, unix_time(SystemTime::now()) as i64)
+        .context("could not record last use")?;
+    config
+        .set_str(URL_KEY, url)
+        .context("could not record url")?;
+
+    Ok(())
+}
+
+impl Directories {
+    /// Lists the bare repositories in the global git cache
+    pub fn cached_repos(&self) -> Result<Vec<CachedRepo>> {
+        let mut dirs = vec![];
+        if self.global_git_repos.exists() {
+            find_bare_repos(&self.global_git_repos, &mut dirs)?;
+        }
+        dirs.sort();
+
+        dirs.iter().map(|dir| cached_repo(dir)).collect()
+    }
+
+    /// Removes the cached repository of `url`.
+    ///
+    /// Fails if worktrees still use it, unless `force` is set.
+    pub fn remove_cached_repo(
+        &self,
+        url: &str,
+        force: bool,
+        reporter: &mut dyn Reporter,
+    ) -> Result<()> {
+        let dir = self.global_git_dir(url)?;
+        if !dir.exists() {
+            bail!("{} is not cached", url);
+        }
+
+        let cached = cached_repo(&dir)?;
+        if !cached.worktrees.is_empty() && !force {
+            bail!(
+                "{} is still used by {} (use --force to remove it anyway)",
+                url,
+                display_paths(&cached.worktrees)
+            );
+        }
+
+        self.remove_repo_dir(&dir, reporter)
+    }
+
+    /// Prunes dangling worktrees, removes repositories that haven't been used for `max_age`
+    /// and have no worktrees left, and runs `git gc` on the remaining ones
+    pub fn gc_cache(&self, max_age: Duration, reporter: &mut dyn Reporter) -> Result<()> {
+        let oldest = unix_time(SystemTime::now()).saturating_sub(max_age.as_secs());
+
+        for dir in self.cached_repos()?.into_iter().map(|c| c.dir) {
+            prune_dangling_worktrees(&dir, reporter)?;
+            let cached = cached_repo(&dir)?;
+
+            let unused = cached.last_used.map(|t| t <= oldest).unwrap_or(true);
+            if unused && cached.worktrees.is_empty() {
+                self.remove_repo_dir(&dir, reporter)?;
+                continue;
+            }
+
+            let status = Command::new("git")
+                .arg("-C")
+                .arg(&dir)
+                .args(["gc", "--quiet"])
+                .status()
+                .context("could not run git gc, is git installed?")?;
+            if !status.success() {
+                bail!("git gc failed for {} ({})", dir.display(), status);
+            }
+            reporter.report(Event::CachedRepoCompacted { dir });
+        }
+
+        Ok(())
+    }
+
+    /// Removes `dir` and the parent directories that are left empty
+    fn remove_repo_dir(&self, dir: &Path, reporter: &mut dyn Reporter) -> Result<()> {
+        remove_dir_all::remove_dir_all(dir)
+            .with_context(|| anyhow!("could not remove {}", dir.display()))?;
+
+        for parent in dir.ancestors().skip(1) {
+            if !parent.starts_with(&self.global_git_repos) || parent == self.global_git_repos {
+                break;
+            }
+            // fails if the directory isn't empty
+            if fs::remove_dir(parent).is_err() {
+                break;
+            }
+        }
+
+        reporter.report(Event::CachedRepoRemoved {
+            dir: dir.to_path_buf(),
+        });
+
+        Ok(())
+    }
+}
+
+fn find_bare_repos(dir: &Path, repos: &mut Vec<PathBuf>) -> Result<()> {
+    if dir.join("HEAD").is_file() && Repository::open_bare(dir).is_ok() {
+        repos.push(dir.to_path_buf());
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(dir).with_context(|| anyhow!("could not list {}", dir.display()))? {
+        let entry = entry.context("could not read directory entry")?;
+        if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+            find_bare_repos(&entry.path(), repos)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn cached_repo(dir: &Path) -> Result<CachedRepo> {
+    let repo =
+        Repository::open_bare(dir).with_context(|| anyhow!("could not open {}", dir.display()))?;
+    let config = repo.config().context("could not open repo config")?;
+
+    let last_used = match config.get_i64(LAST_USED_KEY) {
+        Ok(time) => Some(time as u64),
+        Err(_) => fs::metadata(dir.join("FETCH_HEAD"))
+            .and_then(|m| m.modified())
+            .ok()
+            .map(unix_time),
+    };
+    let url = config
+        .get_string(URL_KEY)
+        .or_else(|_| config.get_string("remote.origin.url"))
+        .ok();
+
+    let mut worktrees = vec![];
+    for name in repo
+        .worktrees()
+        .context("cannot query worktrees")?
+        .iter()
+        .flatten()
+    {
+        let worktree = repo
+            .find_worktree(name)
+            .context("could not open worktree")?;
+        if worktree.validate().is_ok() {
+            worktrees.push(worktree.path().to_path_buf());
+        }
+    }
+
+    Ok(CachedRepo {
+        dir: dir.to_path_buf(),
+        url,
+        size: dir_size(dir)?,
+        last_used,
+        worktrees,
+    })
+}
+
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir).with_context(|| anyhow!("could not list {}", dir.display()))? {
+        let entry = entry.context("could not read directory entry")?;
+        let metadata = entry.metadata().context("could not read file metadata")?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+
+    Ok(size)
+}
+
+fn display_paths(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use git2::Repository;
+
+    use crate::{cache::record_usage, test_util::directories};
+
+    #[test]
+    fn gc_removes_unused_repos() {
+        let dir = tempfile::tempdir().unwrap();
+        let directories = directories(dir.path(), "project");
+        let url = "https://example.com/org/repo.git";
+        let repo_dir = directories.global_git_dir(url).unwrap();
+        let repo = Repository::init_bare(&repo_dir).unwrap();
+        record_usage(&repo, url).unwrap();
+
+        let cached = directories.cached_repos().unwrap();
+        assert_eq!(cached.len(), 1);
+        assert_eq!(cached[0].dir, repo_dir);
+        assert_eq!(cached[0].url.as_deref(), Some(url));
+        assert!(cached[0].worktrees.is_empty());
+
+        let mut events = vec![];
+        directories
+            .gc_cache(Duration::from_secs(3600), &mut |e| events.push(e))
+            .unwrap();
+        assert!(repo_dir.exists());
+
+        directories
+            .gc_cache(Duration::ZERO, &mut |e| events.push(e))
+            .unwrap();
+        assert!(!repo_dir.exists());
+        assert!(!directories.global_git_repos.join("example.com").exists());
+        assert!(directories.cached_repos().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,178 @@
+use std::{collections::VecDeque, env, path::PathBuf};
+
+use git2::{Cred, CredentialType};
+use url::Url;
+
+use crate::{azure, github};
+
+/// Environment variable for a token used for HTTPS remotes on any host
+pub(crate) const TOKEN_ENV: &str = "PKGSTRAP_GIT_TOKEN";
+
+/// Private keys tried if the ssh-agent can't authenticate, in order
+pub(crate) fn default_ssh_keys() -> Vec<PathBuf> {
+    let ssh_dir = dirs::home_dir().unwrap_or_else(|| ".".into()).join(".ssh");
+
+    ["id_ed25519", "id_ecdsa", "id_rsa"]
+        .iter()
+        .map(|name| ssh_dir.join(name))
+        .collect()
+}
+
+/// Resolves credentials for one git operation.
+///
+/// libgit2 asks again whenever authentication fails, so every method is only offered once
+/// and an error listing all attempts is returned when there is nothing left to try.
+pub(crate) struct Credentials {
+    username: bool,
+    agent: bool,
+    helper: bool,
+    ssh_keys: VecDeque<PathBuf>,
+    token: bool,
+    attempts: Vec<String>,
+}
+
+impl Credentials {
+    pub fn new() -> Self {
+        Credentials {
+            username: true,
+            agent: env::var_os("SSH_AUTH_SOCK").is_some(),
+            helper: true,
+            ssh_keys: default_ssh_keys().into(),
+            token: true,
+            attempts: vec![],
+        }
+    }
+
+    pub fn next(
+        &mut self,
+        url: &str,
+        username_from_url: Option<&str>,
+        allowed: CredentialType,
+    ) -> Result<Cred, git2::Error> {
+        let username = username_from_url.unwrap_or("git");
+
+        if allowed.contains(CredentialType::USERNAME) && self.username {
+            self.username = false;
+            return Cred::username(username);
+        }
+
+        if allowed.contains(CredentialType::SSH_KEY) && self.agent {
+            self.agent = false;
+            if let Ok(cred) = Cred::ssh_key_from_agent(username) {
+                self.attempts.push("ssh-agent".to_string());
+                return Ok(cred);
+            }
+        }
+
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) && self.helper {
+            self.helper = false;
+            let cred = git2::Config::open_default()
+                .and_then(|config| Cred::credential_helper(&config, url, username_from_url));
+            if let Ok(cred) = cred {
+                self.attempts.push("git credential helper".to_string());
+                return Ok(cred);
+            }
+        }
+
+        if allowed.contains(CredentialType::SSH_KEY) {
+            while let Some(key) = self.ssh_keys.pop_front() {
+                if key.is_file() {
+                    self.attempts.push(key.display().to_string());
+                    return Cred::ssh_key(username, None, &key, None);
+                }
+            }
+        }
+
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) && self.token {
+            self.token = false;
+            if let Some((env_var, user, token)) = token_for(url, username_from_url) {
+                self.attempts.push(format!("token from {}", env_var));
+                return Cred::userpass_plaintext(&user, &token);
+            }
+        }
+
+        Err(git2::Error::from_str(&if self.attempts.is_empty() {
+            format!("no credentials available for {}", url)
+        } else {
+            format!(
+                "authentication for {} failed, tried: {}",
+                url,
+                self.attempts.join(", ")
+            )
+        }))
+    }
+}
+
+/// Finds a token for an HTTPS remote, preferring the host specific ones
+fn token_for(url: &str, username_from_url: Option<&str>) -> Option<(&'static str, String, String)> {
+    let host = Url::parse(url).ok()?.host_str()?.to_ascii_lowercase();
+
+    let host_specific = if host == "github.com" {
+        Some((github::TOKEN_ENV, "x-access-token"))
+    } else if host == "dev.azure.com" || host.ends_with(".visualstudio.com") {
+        Some((azure::PAT_ENV, "pkgstrap"))
+    } else {
+        None
+    };
+    if let Some((env_var, user)) = host_specific {
+        if let Ok(token) = env::var(env_var) {
+            return Some((env_var, user.to_string(), token));
+        }
+    }
+
+    env::var(TOKEN_ENV).ok().map(|token| {
+        (
+            TOKEN_ENV,
+            username_from_url.unwrap_or("git").to_string(),
+            token,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use git2::CredentialType;
+
+    use crate::credentials::Credentials;
+
+    #[test]
+    fn stops_after_all_attempts() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = dir.path().join("id_ed25519");
+        fs::write(&key, "not really a key").unwrap();
+
+        let mut credentials = Credentials {
+            username: true,
+            agent: true,
+            helper: false,
+            ssh_keys: vec![dir.path().join("id_missing"), key.clone()].into(),
+            token: false,
+            attempts: vec![],
+        };
+        let url = "ssh://git@example.com/repo.git";
+
+        assert!(credentials
+            .next(url, None, CredentialType::USERNAME)
+            .is_ok());
+        assert!(credentials
+            .next(url, Some("git"), CredentialType::SSH_KEY)
+            .is_ok());
+        assert!(credentials
+            .next(url, Some("git"), CredentialType::SSH_KEY)
+            .is_ok());
+        let err = credentials
+            .next(url, Some("git"), CredentialType::SSH_KEY)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.message(),
+            format!(
+                "authentication for {} failed, tried: ssh-agent, {}",
+                url,
+                key.display()
+            )
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use std::env;
+
+use anyhow::{anyhow, bail, Context};
+use glob::Pattern;
+use serde::Deserialize;
+
+use crate::Result;
+
+pub(crate) const DEFAULT_API_URL: &str = "https://api.github.com";
+/// Environment variable for the base url of the REST API, as set by GitHub Actions
+pub(crate) const API_URL_ENV: &str = "GITHUB_API_URL";
+/// Environment variable for the token used to access the REST API
+pub(crate) const TOKEN_ENV: &str = "GITHUB_TOKEN";
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Asset {
+    pub name: String,
+    /// REST API url of the asset, which also works for private repositories
+    pub url: String,
+}
+
+pub(crate) fn api_url() -> String {
+    env::var(API_URL_ENV).unwrap_or_else(|_| DEFAULT_API_URL.to_string())
+}
+
+pub(crate) fn headers(accept: &str) -> Vec<(&'static str, String)> {
+    let mut headers = vec![("Accept", accept.to_string())];
+    if let Ok(token) = env::var(TOKEN_ENV) {
+        headers.push(("Authorization", format!("Bearer {}", token)));
+    }
+
+    headers
+}
+
+/// Finds the single asset of release `tag` whose name matches the glob `pattern`
+pub(crate) fn find_asset(api_url: &str, repo: &str, tag: &str, pattern: &str) -> Result<Asset> {
+    let pattern =
+        Pattern::new(pattern).with_context(|| anyhow!("invalid asset pattern {}", pattern))?;
+    let url = format!(
+        "{}/repos/{}/releases/tags/{}",
+        api_url.trim_end_matches('/'),
+        repo,
+        tag
+    );
+
+    let request = headers("application/vnd.github+json")
+        .iter()
+        .fold(ureq::get(&url), |r, (name, value)| r.set(name, value));
+    let release: Release = request
+        .call()
+        .with_context(|| anyhow!("failed to query release {} of {}", tag, repo))?
+        .into_json()
+        .context("invalid release response")?;
+
+    let mut assets: Vec<Asset> = release
+        .assets
+        .into_iter()
+        .filter(|a| pattern.matches(&a.name))
+        .collect();
+    match assets.len() {
+        1 => Ok(assets.remove(0)),
+        0 => bail!(
+            "no asset of release {} of {} matches {}",
+            tag,
+            repo,
+            pattern
+        ),
+        _ => bail!(
+            "multiple assets of release {} of {} match {}: {}",
+            tag,
+            repo,
+            pattern,
+            assets
+                .iter()
+                .map(|a| a.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use tiny_http::{Response, Server};
+
+    use crate::github::find_asset;
+
+    /// Serves a release with the given asset names
+    fn mock_api(assets: &'static [&'static str]) -> String {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let api_url = format!("http://{}", server.server_addr());
+
+        let assets_json: Vec<String> = assets
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                format!(
+                    r#"{{"name": "{}", "url": "{}/repos/o/r/releases/assets/{}"}}"#,
+                    name, api_url, i
+                )
+            })
+            .collect();
+        let release = format!(
+            r#"{{"tag_name": "v1.0", "assets": [{}]}}"#,
+            assets_json.join(",")
+        );
+
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let response = if request.url() == "/repos/o/r/releases/tags/v1.0" {
+                    Response::from_string(release.clone())
+                } else {
+                    Response::from_string("not found").with_status_code(404)
+                };
+                request.respond(response).unwrap();
+            }
+        });
+
+        api_url
+    }
+
+    #[test]
+    fn find_release_asset() {
+        let api_url = mock_api(&["tool-linux-x64.tar.gz", "tool-windows-x64.zip"]);
+
+        let asset = find_asset(&api_url, "o/r", "v1.0", "*-linux-*").unwrap();
+        assert_eq!(asset.name, "tool-linux-x64.tar.gz");
+        assert_eq!(
+            asset.url,
+            format!("{}/repos/o/r/releases/assets/0", api_url)
+        );
+
+        assert!(find_asset(&api_url, "o/r", "v1.0", "*-x64*").is_err());
+        assert!(find_asset(&api_url, "o/r", "v1.0", "*-macos-*").is_err());
+        assert!(find_asset(&api_url, "o/r", "v2.0", "*").is_err());
+    }
+}
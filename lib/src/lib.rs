@@ -1,28 +1,109 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
+use anyhow::bail;
 use git2::Reference;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
+use crate::partial::Partial;
+
+mod artifact;
+mod azure;
+mod cache;
+mod credentials;
+mod github;
+mod lock;
+mod partial;
+mod report;
 mod resolved;
+mod stale;
+mod status;
+#[cfg(test)]
+mod test_util;
+mod warning;
 
 pub use anyhow::{Error, Result};
 
-pub use self::resolved::{DependencyDirs, ResolvedDependency, Resolver};
+pub use self::{
+    cache::CachedRepo,
+    lock::{LockFile, LockedDependency, Pinned},
+    report::{Event, Progress, Reporter},
+    resolved::{
+        check_local_path, prune_dangling_worktrees, remove_symlink, remove_worktree,
+        AcquireOptions, DependencyDirs, DirtyWorktree, ResolvedDependency, Resolver,
+    },
+    stale::StaleDependency,
+    status::{DependencyStatus, LinkStatus},
+    warning::Warning,
+};
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
     pub dependencies: HashMap<String, Dependency>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Dependency {
     pub source: DependencySource,
     pub target: Option<PathBuf>,
+    /// Number of commits of history to fetch for git repositories
+    pub depth: Option<u32>,
+    /// Partial clone filter for git repositories, e.g. `blob:none`
+    pub filter: Option<String>,
+    /// Directories to check out of git repositories with a sparse checkout, all if empty
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Directory inside of the checkout or local path to link instead of its root
+    pub subdir: Option<PathBuf>,
+    /// Additional in-tree symlinks to the dependency, e.g. `third_party/foo`
+    #[serde(default)]
+    pub links: Vec<PathBuf>,
 }
 
-impl Dependency {}
+impl Dependency {
+    /// Clones this dependency into `target` as a regular repository for local development.
+    ///
+    /// With `offline`, only the objects in the global bare repository are used.
+    pub fn clone_into(
+        &self,
+        dirs: &Directories,
+        target: &Path,
+        offline: bool,
+        reporter: &mut dyn Reporter,
+    ) -> Result<()> {
+        match &self.source {
+            DependencySource::GitRepository { git_repo, git_ref } => {
+                let partial = Partial {
+                    depth: self.depth,
+                    filter: self.filter.as_deref(),
+                };
+                resolved::clone_working_copy(
+                    dirs, git_repo, git_ref, partial, target, offline, reporter,
+                )
+            }
+            _ => bail!("only git dependencies can be cloned"),
+        }
+    }
+
+    /// Fails unless `path` is a clone of this git dependency with at least one commit
+    pub fn check_clone(&self, path: &Path) -> Result<()> {
+        match self.source.git_repo_url() {
+            Some(git_repo) => resolved::check_working_copy(path, git_repo),
+            None => bail!("only git dependencies can be cloned"),
+        }
+    }
 
-#[derive(Clone, Debug, Deserialize)]
+    /// Returns the remote url of the checkout at `local_path` if it does not match this dependency
+    pub fn mismatched_remote(&self, local_path: &Path) -> Option<String> {
+        let git_repo = self.source.git_repo_url()?;
+
+        resolved::mismatched_remote(local_path, git_repo)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(untagged)]
 pub enum DependencySource {
     GitRepository {
@@ -30,22 +111,53 @@ pub enum DependencySource {
         #[serde(flatten)]
         git_ref: GitRef,
     },
+    /// A `.tar.gz`, `.tar` or `.zip` archive, downloaded from a `http(s)://` or `file://` url
+    Archive {
+        url: String,
+        sha256: String,
+        /// Directory inside of the archive to use as root
+        strip_prefix: Option<PathBuf>,
+    },
+    /// An asset of a GitHub release, which is unpacked if it is an archive
+    GithubRelease {
+        /// `<owner>/<name>`
+        github_repo: String,
+        tag: String,
+        /// Glob pattern that must match exactly one asset name, e.g. `*-linux-x64.tar.gz`
+        asset: String,
+        sha256: Option<String>,
+        /// Base url of the REST API, defaults to `$GITHUB_API_URL` or `https://api.github.com`
+        github_api: Option<String>,
+    },
+    /// A universal package from Azure Artifacts, downloaded with the `az` CLI
+    AzureArtifact {
+        azure_organization: String,
+        /// Project of project-scoped feeds
+        azure_project: Option<String>,
+        feed: String,
+        package: String,
+        /// Exact version or version requirement, e.g. `^1.2`
+        version: String,
+        /// Base url replacing the Azure DevOps hosts, defaults to `$AZURE_DEVOPS_API_URL`
+        azure_api: Option<String>,
+    },
 }
 
 impl DependencySource {
     pub fn git_repo_url(&self) -> Option<&String> {
         match self {
             DependencySource::GitRepository { git_repo, .. } => Some(git_repo),
+            _ => None,
         }
     }
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
 pub struct ConfigOverrides {
     pub dependencies: HashMap<String, DependencyOverride>,
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 #[serde(untagged)]
 pub enum DependencyOverride {
     LocalPath {
@@ -64,27 +176,68 @@ pub struct Directories {
     pub deps_dir: PathBuf,
     pub local_git_workdirs: PathBuf,
     pub global_git_repos: PathBuf,
+    /// Content-addressed cache of downloaded artifacts
+    pub global_artifacts: PathBuf,
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 #[serde(untagged)]
 pub enum GitRef {
-    Branch { branch: String },
-    Tag { tag: String },
-    Commit { branch: String, commit: String },
+    // must come before `Branch`, otherwise `commit` would be ignored
+    /// A commit that must be on `branch`
+    Commit {
+        branch: String,
+        commit: String,
+    },
+    // must come after `Commit`, otherwise `branch` would be ignored
+    /// A commit, which may be abbreviated
+    CommitOnly {
+        commit: String,
+    },
+    Branch {
+        branch: String,
+    },
+    Tag {
+        tag: String,
+    },
+    /// The highest tag matching a semver requirement like `^1.3`, ignoring prefixes like `v`
+    Version {
+        version: String,
+    },
 }
 
 impl GitRef {
+    /// Refspec to fetch, which also updates the ref used by [`Self::to_checkout_refspec`].
+    ///
+    /// For [`GitRef::CommitOnly`], this is the commit id itself.
     pub fn to_fetch_ref(&self) -> String {
-        let r = match self {
-            GitRef::Branch { branch } => branch.clone(),
-            GitRef::Tag { tag } => tag.clone(),
-            GitRef::Commit { branch, .. } => branch.clone(),
-        };
+        match self {
+            GitRef::CommitOnly { commit } => commit.clone(),
+            GitRef::Branch { branch } | GitRef::Commit { branch, .. } => {
+                format!("+refs/heads/{0}:refs/remotes/origin/{0}", branch)
+            }
+            GitRef::Tag { tag } => format!("+refs/tags/{0}:refs/tags/{0}", tag),
+            GitRef::Version { .. } => "+refs/tags/*:refs/tags/*".to_string(),
+        }
+    }
 
-        r
+    /// Ref that must contain the commit checked out for [`GitRef::Commit`]
+    pub fn to_containing_ref(&self) -> Option<String> {
+        match self {
+            GitRef::Commit { branch, .. } => Some(format!("refs/remotes/origin/{}", branch)),
+            _ => None,
+        }
     }
 
+    /// Semver requirement to select one of the tags matched by [`Self::to_checkout_refspec`]
+    pub fn version(&self) -> Option<&String> {
+        match self {
+            GitRef::Version { version } => Some(version),
+            _ => None,
+        }
+    }
+
+    /// Ref to check out, or a glob of the candidate tags for [`GitRef::Version`]
     pub fn to_checkout_refspec(&self) -> String {
         let r = match self {
             GitRef::Branch { branch } => {
@@ -93,10 +246,11 @@ impl GitRef {
             GitRef::Tag { tag } => {
                 format!("refs/tags/{}", tag)
             }
-            GitRef::Commit { commit, .. } => {
+            GitRef::Commit { commit, .. } | GitRef::CommitOnly { commit } => {
                 // Skip assertion
                 return commit.clone();
             }
+            GitRef::Version { .. } => return "refs/tags/*".to_string(),
         };
 
         assert!(Reference::is_valid_name(&r));
@@ -109,6 +263,38 @@ impl GitRef {
 mod tests {
     use crate::GitRef;
 
+    #[test]
+    fn fetch_refs() {
+        assert_eq!(
+            GitRef::Branch {
+                branch: "main".to_string()
+            }
+            .to_fetch_ref(),
+            "+refs/heads/main:refs/remotes/origin/main"
+        );
+        assert_eq!(
+            GitRef::Tag {
+                tag: "1.0.0".to_string()
+            }
+            .to_fetch_ref(),
+            "+refs/tags/1.0.0:refs/tags/1.0.0"
+        );
+        assert_eq!(
+            GitRef::Version {
+                version: "^1.3".to_string()
+            }
+            .to_fetch_ref(),
+            "+refs/tags/*:refs/tags/*"
+        );
+        assert_eq!(
+            GitRef::CommitOnly {
+                commit: "12f123".to_string()
+            }
+            .to_fetch_ref(),
+            "12f123"
+        );
+    }
+
     #[test]
     fn checkout_refs() {
         assert_eq!(
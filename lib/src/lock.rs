@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+
+use git2::Oid;
+use serde::{Deserialize, Serialize};
+
+use crate::{DependencySource, GitRef};
+
+/// Contents of `pkgstrap-lock.ron`
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct LockFile {
+    pub dependencies: BTreeMap<String, LockedDependency>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum LockedDependency {
+    GitRepository {
+        git_repo: String,
+        /// The ref as requested in the config at the time of locking
+        git_ref: GitRef,
+        /// Full id of the commit that was checked out
+        commit: String,
+        /// The tag selected by a version requirement
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tag: Option<String>,
+    },
+    GithubRelease {
+        github_repo: String,
+        tag: String,
+        /// The asset pattern as requested in the config at the time of locking
+        asset: String,
+        /// Checksum of the asset that was downloaded
+        sha256: String,
+    },
+    AzureArtifact {
+        azure_organization: String,
+        feed: String,
+        package: String,
+        /// The version (requirement) as requested in the config at the time of locking
+        version: String,
+        resolved_version: String,
+        sha256: String,
+    },
+}
+
+/// What an acquired dependency was pinned to, used to update the lock file
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pinned {
+    Commit(Oid),
+    /// A commit selected by its tag
+    Tag {
+        tag: String,
+        commit: Oid,
+    },
+    Sha256(String),
+    Package {
+        version: String,
+        sha256: String,
+    },
+}
+
+impl LockedDependency {
+    /// Creates a lock entry for `source`, if `pinned` applies to it
+    pub fn new(source: &DependencySource, pinned: &Pinned) -> Option<Self> {
+        let locked = match (source, pinned) {
+            (DependencySource::GitRepository { git_repo, git_ref }, Pinned::Commit(commit)) => {
+                LockedDependency::GitRepository {
+                    git_repo: git_repo.clone(),
+                    git_ref: git_ref.clone(),
+                    commit: commit.to_string(),
+                    tag: None,
+                }
+            }
+            (
+                DependencySource::GitRepository { git_repo, git_ref },
+                Pinned::Tag { tag, commit },
+            ) => LockedDependency::GitRepository {
+                git_repo: git_repo.clone(),
+                git_ref: git_ref.clone(),
+                commit: commit.to_string(),
+                tag: Some(tag.clone()),
+            },
+            (
+                DependencySource::GithubRelease {
+                    github_repo,
+                    tag,
+                    asset,
+                    ..
+                },
+                Pinned::Sha256(sha256),
+            ) => LockedDependency::GithubRelease {
+                github_repo: github_repo.clone(),
+                tag: tag.clone(),
+                asset: asset.clone(),
+                sha256: sha256.clone(),
+            },
+            (
+                DependencySource::AzureArtifact {
+                    azure_organization,
+                    feed,
+                    package,
+                    version,
+                    ..
+                },
+                Pinned::Package {
+                    version: resolved_version,
+                    sha256,
+                },
+            ) => LockedDependency::AzureArtifact {
+                azure_organization: azure_organization.clone(),
+                feed: feed.clone(),
+                package: package.clone(),
+                version: version.clone(),
+                resolved_version: resolved_version.clone(),
+                sha256: sha256.clone(),
+            },
+            _ => return None,
+        };
+
+        Some(locked)
+    }
+
+    /// Returns whether this entry was locked for the given specification
+    pub fn matches(&self, source: &DependencySource) -> bool {
+        match (self, source) {
+            (
+                LockedDependency::GitRepository {
+                    git_repo, git_ref, ..
+                },
+                DependencySource::GitRepository {
+                    git_repo: source_git_repo,
+                    git_ref: source_git_ref,
+                },
+            ) => git_repo == source_git_repo && git_ref == source_git_ref,
+            (
+                LockedDependency::GithubRelease {
+                    github_repo,
+                    tag,
+                    asset,
+                    ..
+                },
+                DependencySource::GithubRelease {
+                    github_repo: source_github_repo,
+                    tag: source_tag,
+                    asset: source_asset,
+                    ..
+                },
+            ) => github_repo == source_github_repo && tag == source_tag && asset == source_asset,
+            (
+                LockedDependency::AzureArtifact {
+                    azure_organization,
+                    feed,
+                    package,
+                    version,
+                    ..
+                },
+                DependencySource::AzureArtifact {
+                    azure_organization: source_azure_organization,
+                    feed: source_feed,
+                    package: source_package,
+                    version: source_version,
+                    ..
+                },
+            ) => {
+                azure_organization == source_azure_organization
+                    && feed == source_feed
+                    && package == source_package
+                    && version == source_version
+            }
+            _ => false,
+        }
+    }
+}
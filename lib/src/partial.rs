@@ -0,0 +1,284 @@
+use std::{
+    collections::BTreeSet,
+    env, fs,
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, bail, Context};
+use git2::{Oid, Repository};
+
+use crate::Result;
+
+/// Config key of the deepest history requested for a shallow repo, so that dependencies
+/// sharing the global bare repository never make it shallower
+const DEPTH_KEY: &str = "pkgstrap.depth";
+
+/// Sparse checkout settings, passed to every command instead of being stored in the config,
+/// because git would enable `extensions.worktreeConfig` for them, which libgit2 rejects
+const SPARSE_CONFIG: [&str; 4] = [
+    "-c",
+    "core.sparseCheckout=true",
+    "-c",
+    "core.sparseCheckoutCone=true",
+];
+
+/// How much of a git repository a dependency asks for.
+///
+/// libgit2 supports neither shallow nor partial clones, so repos using them are
+/// cloned and fetched with the `git` executable instead.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Partial<'a> {
+    pub depth: Option<u32>,
+    pub filter: Option<&'a str>,
+}
+
+impl Partial<'_> {
+    pub fn is_full(&self) -> bool {
+        self.depth.is_none() && self.filter.is_none()
+    }
+
+    /// Whether `repo` has to be fetched with the `git` executable
+    pub fn uses_git(&self, repo: &Repository) -> bool {
+        !self.is_full() || repo.is_shallow() || is_partial(repo)
+    }
+
+    /// Clones `url` into `dir` with the `git` executable
+    pub fn clone(&self, url: &str, dir: &Path, bare: bool) -> Result<Repository> {
+        let mut command = Command::new("git");
+        command.args(["clone", "--quiet", "--no-checkout"]);
+        if bare {
+            command.arg("--bare");
+        }
+        if let Some(depth) = self.depth {
+            command.arg(format!("--depth={}", depth));
+        }
+        if let Some(filter) = self.filter {
+            command.arg(format!("--filter={}", filter));
+        }
+        run(command.arg(url).arg(dir))?;
+
+        let repo = Repository::open(dir).context("could not open cloned repo")?;
+        if let Some(depth) = self.depth {
+            repo.config()
+                .and_then(|mut c| c.set_i64(DEPTH_KEY, depth.into()))
+                .context("could not record clone depth")?;
+        }
+
+        Ok(repo)
+    }
+
+    /// Fetches `refspecs` from `url` into `repo` with the `git` executable.
+    ///
+    /// A shallow repo is deepened to the deepest depth requested so far, or unshallowed
+    /// if this dependency wants the full history.
+    pub fn fetch(&self, repo: &Repository, url: &str, refspecs: &[&str]) -> Result<()> {
+        let mut command = git(repo);
+        command.args(["fetch", "--quiet", "--no-write-fetch-head"]);
+        if repo.is_shallow() {
+            match self.depth {
+                Some(depth) => {
+                    let mut config = repo.config().context("could not open repo config")?;
+                    let recorded = config.get_i64(DEPTH_KEY).unwrap_or(0);
+                    let depth = recorded.max(depth.into());
+                    config
+                        .set_i64(DEPTH_KEY, depth)
+                        .context("could not record fetch depth")?;
+                    command.arg(format!("--depth={}", depth));
+                }
+                None => {
+                    command.arg("--unshallow");
+                }
+            }
+        }
+
+        // a partial repo only accepts filtered fetches from its promisor remote
+        let remote = match repo.find_remote("origin") {
+            Ok(_) => "origin",
+            Err(_) => url,
+        };
+        run(command.arg(remote).args(refspecs))
+            .with_context(|| anyhow!("failed to fetch from {}", url))?;
+
+        Ok(())
+    }
+}
+
+pub(crate) fn is_partial(repo: &Repository) -> bool {
+    repo.config()
+        .and_then(|c| c.get_bool("remote.origin.promisor"))
+        .unwrap_or(false)
+}
+
+/// Downloads the blobs of `commit` missing from the partial repo `repo`, which libgit2
+/// can't fetch on demand when checking out
+pub(crate) fn fetch_missing_blobs(repo: &Repository, commit: Oid) -> Result<()> {
+    if !is_partial(repo) {
+        return Ok(());
+    }
+
+    let objects = run(git(repo)
+        .args(["rev-list", "--objects", "--missing=print", "--no-walk"])
+        .arg(commit.to_string()))?;
+    let missing: Vec<_> = objects
+        .lines()
+        .filter_map(|l| l.strip_prefix('?'))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    // the same request git makes when it fetches missing objects itself
+    let mut child = git(repo)
+        .args([
+            "-c",
+            "fetch.negotiationAlgorithm=noop",
+            "fetch",
+            "--quiet",
+            "origin",
+        ])
+        .args([
+            "--no-tags",
+            "--no-write-fetch-head",
+            "--recurse-submodules=no",
+        ])
+        .args(["--filter=blob:none", "--stdin"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("could not run git")?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(missing.join("\n").as_bytes())
+        .context("could not write to git")?;
+    let status = child.wait().context("could not run git")?;
+    if !status.success() {
+        bail!(
+            "failed to fetch {} missing object(s) of {}",
+            missing.len(),
+            commit
+        );
+    }
+
+    Ok(())
+}
+
+/// Restricts the worktree `repo` to the directories `paths` with a cone mode sparse
+/// checkout, or checks out everything again if `paths` is empty
+pub(crate) fn set_sparse_paths(repo: &Repository, paths: &[String]) -> Result<()> {
+    let patterns = repo.path().join("info").join("sparse-checkout");
+    if paths.is_empty() {
+        if patterns.exists() {
+            // matching everything clears the skip-worktree bits in the index
+            fs::write(&patterns, "/*\n").context("could not write sparse checkout patterns")?;
+            run(git(repo).args(["-c", "core.sparseCheckout=true", "read-tree", "-mu", "HEAD"]))
+                .context("could not disable sparse checkout")?;
+            fs::remove_file(&patterns).context("could not remove sparse checkout patterns")?;
+        }
+        return Ok(());
+    }
+
+    let wanted: BTreeSet<_> = paths.iter().map(|p| p.trim_matches('/')).collect();
+    if patterns.exists() {
+        let current = run(git(repo)
+            .args(SPARSE_CONFIG)
+            .args(["sparse-checkout", "list"]))?;
+        if current.lines().collect::<BTreeSet<_>>() == wanted {
+            return Ok(());
+        }
+    }
+
+    // without `--cone`, which would record the mode in the config
+    run(git(repo)
+        .args(SPARSE_CONFIG)
+        .args(["sparse-checkout", "set", "--"])
+        .args(wanted))
+    .context("could not set sparse checkout paths")?;
+
+    Ok(())
+}
+
+/// Adds a worktree of the bare `repo` at `dir` with the `git` executable and checks out
+/// `commit` in it, restricted to `paths` if there are any.
+///
+/// libgit2 would check out HEAD of `repo` right away, writing every file of it and
+/// fetching all of its missing blobs before the sparse patterns could be applied.
+pub(crate) fn add_worktree(
+    repo: &Repository,
+    dir: &Path,
+    commit: Oid,
+    paths: &[String],
+) -> Result<Repository> {
+    // `git -C` would resolve a relative path against the global repo
+    let dir = env::current_dir()
+        .context("could not get current directory")?
+        .join(dir);
+    // `--force` reuses the registration of a worktree whose directory was deleted
+    run(git(repo)
+        .args([
+            "worktree",
+            "add",
+            "--quiet",
+            "--no-checkout",
+            "--detach",
+            "--force",
+        ])
+        .arg(&dir)
+        .arg(commit.to_string()))
+    .context("failed to create worktree")?;
+
+    let worktree = Repository::open(&dir).context("could not open local worktree")?;
+    set_sparse_paths(&worktree, paths)?;
+    let mut command = git(&worktree);
+    if !paths.is_empty() {
+        command.args(SPARSE_CONFIG);
+    }
+    run(command
+        .args(["checkout", "--quiet", "--force", "--detach"])
+        .arg(commit.to_string()))
+    .with_context(|| anyhow!("could not check out {}", commit))?;
+
+    Ok(worktree)
+}
+
+/// Checks out `commit` in the sparse worktree `repo`, detaching HEAD
+pub(crate) fn checkout_sparse(repo: &Repository, commit: Oid, force: bool) -> Result<()> {
+    let mut command = git(repo);
+    command
+        .args(SPARSE_CONFIG)
+        .args(["checkout", "--quiet", "--detach"]);
+    if force {
+        command.arg("--force");
+    }
+    run(command.arg(commit.to_string()))
+        .with_context(|| anyhow!("could not check out {}", commit))?;
+
+    Ok(())
+}
+
+fn git(repo: &Repository) -> Command {
+    let mut command = Command::new("git");
+    command
+        .arg("-C")
+        .arg(repo.workdir().unwrap_or_else(|| repo.path()));
+    command
+}
+
+/// Runs `command`, returning its output
+fn run(command: &mut Command) -> Result<String> {
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .context("could not run git, which is required for depth, filter and paths")?;
+    if !output.status.success() {
+        bail!(
+            "git failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    String::from_utf8(output.stdout).context("unexpected output from git")
+}
@@ -0,0 +1,175 @@
+use std::{fmt, path::PathBuf};
+
+use serde::Serialize;
+
+/// Something that happened while acquiring a dependency
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The global bare repository is cloned for the first time
+    Cloning {
+        url: String,
+        dir: PathBuf,
+    },
+    Fetching {
+        url: String,
+    },
+    /// The local worktree belonged to another repository and was replaced
+    WorktreeReplaced {
+        dir: PathBuf,
+    },
+    LeftoverWorktreeRemoved {
+        dir: PathBuf,
+    },
+    /// Stale worktree metadata was removed from the global bare repository
+    InvalidWorktreeRemoved {
+        name: String,
+    },
+    ChangesStashed {
+        files: usize,
+    },
+    ChangesDiscarded {
+        files: usize,
+    },
+    /// The highest tag matching a version requirement
+    TagSelected {
+        tag: String,
+    },
+    AtCommit {
+        commit: String,
+    },
+    HeadUpdated {
+        from: String,
+        to: String,
+    },
+    Downloading {
+        url: String,
+    },
+    LinkedArchive {
+        sha256: String,
+    },
+    LinkedReleaseAsset {
+        sha256: String,
+    },
+    LinkedPackage {
+        version: String,
+    },
+    LinkedLocalPath {
+        local_path: PathBuf,
+    },
+    LinkRemoved {
+        link: PathBuf,
+    },
+    WorktreeRemoved {
+        dir: PathBuf,
+    },
+    /// A worktree that should be removed has uncommitted changes
+    WorktreeKept {
+        dir: PathBuf,
+        files: usize,
+    },
+    /// A bare repository was removed from the global cache
+    CachedRepoRemoved {
+        dir: PathBuf,
+    },
+    CachedRepoCompacted {
+        dir: PathBuf,
+    },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Cloning { dir, .. } => write!(f, "cloning into {}...", dir.display()),
+            Event::Fetching { url } => write!(f, "fetching {}...", url),
+            Event::WorktreeReplaced { .. } => write!(f, "replacing worktree due to repo mismatch"),
+            Event::LeftoverWorktreeRemoved { .. } => {
+                write!(f, "removing leftover git worktree files")
+            }
+            Event::InvalidWorktreeRemoved { .. } => {
+                write!(f, "removing existing invalid worktree from repo")
+            }
+            Event::ChangesStashed { files } => write!(f, "stashed changes to {} file(s)", files),
+            Event::ChangesDiscarded { files } => {
+                write!(f, "discarding changes to {} file(s)", files)
+            }
+            Event::TagSelected { tag } => write!(f, "selected tag {}", tag),
+            Event::AtCommit { commit } => write!(f, "at commit {}", commit),
+            Event::HeadUpdated { from, to } => {
+                write!(f, "updated HEAD to commit {} (from {})", to, from)
+            }
+            Event::Downloading { url } => write!(f, "downloading {}...", url),
+            Event::LinkedArchive { sha256 } => write!(f, "linked to archive {}", sha256),
+            Event::LinkedReleaseAsset { sha256 } => {
+                write!(f, "linked to release asset {}", sha256)
+            }
+            Event::LinkedPackage { version } => write!(f, "linked to package version {}", version),
+            Event::LinkedLocalPath { local_path } => {
+                write!(f, "linked to {}", local_path.display())
+            }
+            Event::LinkRemoved { link } => write!(f, "removed link {}", link.display()),
+            Event::WorktreeRemoved { dir } => write!(f, "removed worktree {}", dir.display()),
+            Event::WorktreeKept { dir, files } => write!(
+                f,
+                "kept worktree {} with uncommitted changes to {} file(s)",
+                dir.display(),
+                files
+            ),
+            Event::CachedRepoRemoved { dir } => write!(f, "removed cached repo {}", dir.display()),
+            Event::CachedRepoCompacted { dir } => {
+                write!(f, "compacted cached repo {}", dir.display())
+            }
+        }
+    }
+}
+
+/// Progress of a long running git operation
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(tag = "progress", rename_all = "snake_case")]
+pub enum Progress {
+    Receiving {
+        received_objects: usize,
+        total_objects: usize,
+        received_bytes: usize,
+    },
+    /// Resolving deltas after all objects have been received
+    Indexing {
+        indexed_deltas: usize,
+        total_deltas: usize,
+    },
+    Checkout {
+        completed: usize,
+        total: usize,
+    },
+}
+
+impl Progress {
+    pub(crate) fn transfer(stats: &git2::Progress) -> Self {
+        if stats.received_objects() < stats.total_objects() || stats.total_deltas() == 0 {
+            Progress::Receiving {
+                received_objects: stats.received_objects(),
+                total_objects: stats.total_objects(),
+                received_bytes: stats.received_bytes(),
+            }
+        } else {
+            Progress::Indexing {
+                indexed_deltas: stats.indexed_deltas(),
+                total_deltas: stats.total_deltas(),
+            }
+        }
+    }
+}
+
+/// Receives the events of acquiring a dependency, e.g. to print them
+pub trait Reporter {
+    fn report(&mut self, event: Event);
+
+    /// Called frequently while cloning, fetching and checking out
+    fn progress(&mut self, _progress: Progress) {}
+}
+
+impl<F: FnMut(Event)> Reporter for F {
+    fn report(&mut self, event: Event) {
+        self(event)
+    }
+}
@@ -1,23 +1,46 @@
 use std::{
     collections::HashMap,
-    fs::create_dir_all,
-    path::{Path, PathBuf},
+    fs::{self, create_dir_all},
+    path::{Component, Path, PathBuf},
     str::FromStr,
 };
 
 use anyhow::{anyhow, bail, Context};
 use git2::{
-    build::CheckoutBuilder, BranchType, Cred, RemoteCallbacks, Repository, Worktree,
-    WorktreePruneOptions,
+    build::CheckoutBuilder, BranchType, IndexEntryExtendedFlag, Oid, RemoteCallbacks, Repository,
+    Signature, StashFlags, Status, StatusOptions, Worktree, WorktreePruneOptions,
 };
+use semver::{Version, VersionReq};
 use url::Url;
 
-use crate::{Config, ConfigOverrides, DependencyOverride, DependencySource, Directories, Result};
+use crate::{
+    azure,
+    cache::record_usage,
+    credentials::Credentials,
+    github,
+    partial::{
+        add_worktree, checkout_sparse, fetch_missing_blobs, is_partial, set_sparse_paths, Partial,
+    },
+    report::{Event, Progress, Reporter},
+    Config, ConfigOverrides, Dependency, DependencyOverride, DependencySource, Directories, GitRef,
+    LockFile, LockedDependency, Pinned, Result, Warning,
+};
 
 #[derive(Debug)]
 pub struct Resolver {
     config: Config,
     config_overrides: Option<ConfigOverrides>,
+    lock_file: Option<LockFile>,
+    frozen: bool,
+    /// Dependencies found in configs of acquired dependencies
+    transitive: HashMap<String, Requirement>,
+}
+
+#[derive(Debug)]
+struct Requirement {
+    dependency: Dependency,
+    /// Chain of dependency names that led to this requirement
+    path: Vec<String>,
 }
 
 impl Resolver {
@@ -25,6 +48,9 @@ impl Resolver {
         Resolver {
             config,
             config_overrides: None,
+            lock_file: None,
+            frozen: false,
+            transitive: HashMap::new(),
         }
     }
 
@@ -34,53 +60,337 @@ impl Resolver {
         self
     }
 
+    pub fn with_lock_file(mut self, lock_file: LockFile) -> Self {
+        self.lock_file = Some(lock_file);
+
+        self
+    }
+
+    /// In frozen mode, dependencies present in the lock file are resolved to the locked commit.
+    pub fn frozen(mut self, frozen: bool) -> Self {
+        self.frozen = frozen;
+
+        self
+    }
+
+    pub(crate) fn locked(
+        &self,
+        name: &str,
+        source: &DependencySource,
+    ) -> Option<&LockedDependency> {
+        self.lock_file
+            .as_ref()
+            .and_then(|l| l.dependencies.get(name))
+            .filter(|l| l.matches(source))
+    }
+
+    pub(crate) fn is_overridden(&self, name: &str) -> bool {
+        self.config_overrides
+            .as_ref()
+            .map(|o| o.dependencies.contains_key(name))
+            .unwrap_or(false)
+    }
+
+    /// Creates the new lock file from what the acquired dependencies were pinned to.
+    ///
+    /// Overridden dependencies keep their previous lock entry, since overrides are local only.
+    pub fn lock_file(&self, pins: &HashMap<String, Pinned>) -> LockFile {
+        let mut lock_file = LockFile::default();
+
+        for (name, dependency) in self.dependencies() {
+            let source = &dependency.source;
+            let locked = match (pins.get(name), self.locked(name, source)) {
+                // a frozen run only knows the commit, not the tag it was selected by
+                (
+                    Some(Pinned::Commit(pinned)),
+                    Some(locked @ LockedDependency::GitRepository { commit, .. }),
+                ) if commit == &pinned.to_string() => Some(locked.clone()),
+                (Some(pinned), _) if !self.is_overridden(name) => {
+                    LockedDependency::new(source, pinned)
+                }
+                (_, locked) => locked.cloned(),
+            };
+
+            if let Some(locked) = locked {
+                lock_file.dependencies.insert(name.clone(), locked);
+            }
+        }
+
+        lock_file
+    }
+
+    /// Soft conditions that make the setup less reproducible, which are errors in pedantic mode
+    pub fn warnings(&self, project_root: &Path) -> Vec<Warning> {
+        let mut dependencies: Vec<_> = self.dependencies().collect();
+        dependencies.sort_by_key(|(name, _)| *name);
+
+        let mut warnings = vec![];
+        for (name, dependency) in dependencies {
+            let source = &dependency.source;
+            if !self.is_overridden(name) && self.locked(name, source).is_none() {
+                match source {
+                    DependencySource::GitRepository {
+                        git_ref: GitRef::Branch { branch },
+                        ..
+                    } => warnings.push(Warning::UnpinnedBranch {
+                        dependency: name.clone(),
+                        branch: branch.clone(),
+                    }),
+                    DependencySource::GitRepository { .. }
+                    | DependencySource::GithubRelease { sha256: None, .. }
+                    | DependencySource::AzureArtifact { .. } => {
+                        warnings.push(Warning::MissingLockEntry {
+                            dependency: name.clone(),
+                        })
+                    }
+                    // pinned by their checksum already
+                    DependencySource::Archive { .. } | DependencySource::GithubRelease { .. } => {}
+                }
+            }
+
+            for target in dependency.target.iter().chain(&dependency.links) {
+                if !is_inside(project_root, target) {
+                    warnings.push(Warning::TargetOutsideRoot {
+                        dependency: name.clone(),
+                        target: target.clone(),
+                    });
+                }
+            }
+        }
+
+        warnings
+    }
+
+    /// All dependencies, including the ones found in configs of acquired dependencies
+    pub fn dependencies(&self) -> impl Iterator<Item = (&String, &Dependency)> {
+        self.config.dependencies.iter().chain(
+            self.transitive
+                .iter()
+                .map(|(name, requirement)| (name, &requirement.dependency)),
+        )
+    }
+
+    pub fn dependency(&self, name: &str) -> Option<&Dependency> {
+        self.config
+            .dependencies
+            .get(name)
+            .or_else(|| self.transitive.get(name).map(|r| &r.dependency))
+    }
+
+    /// Chain of dependency names leading to `name`, starting at the root config
+    fn requirement_path(&self, name: &str) -> Vec<String> {
+        match self.transitive.get(name) {
+            Some(requirement) => requirement.path.clone(),
+            None => vec![name.to_string()],
+        }
+    }
+
+    /// Adds the dependencies from the config of the acquired dependency `parent`.
+    ///
+    /// Dependencies are deduplicated by their normalized source; requiring the same source
+    /// differently is an error unless it is overridden.
+    /// Returns the names of dependencies that weren't known before.
+    pub fn add_nested_config(&mut self, parent: &str, config: Config) -> Result<Vec<String>> {
+        let parent_path = self.requirement_path(parent);
+        let mut nested: Vec<_> = config.dependencies.into_iter().collect();
+        nested.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut added = vec![];
+        for (name, mut dependency) in nested {
+            let mut path = parent_path.clone();
+            path.push(name.clone());
+
+            let key = source_key(&dependency.source);
+            let existing = self
+                .dependencies()
+                .find(|(_, d)| source_key(&d.source) == key)
+                .map(|(n, d)| (n.clone(), d.source.clone()));
+            match existing {
+                Some((existing_name, existing_source)) => {
+                    let same = match (&existing_source, &dependency.source) {
+                        (
+                            DependencySource::GitRepository { git_ref: a, .. },
+                            DependencySource::GitRepository { git_ref: b, .. },
+                        ) => a == b,
+                        (a, b) => a == b,
+                    };
+                    if !same && !self.is_overridden(&existing_name) {
+                        bail!(
+                            "conflicting requirements for {}:\n    {} requires {}\n    {} requires {}",
+                            key,
+                            self.requirement_path(&existing_name).join(" -> "),
+                            describe_requirement(&existing_source),
+                            path.join(" -> "),
+                            describe_requirement(&dependency.source)
+                        );
+                    }
+                }
+                None => {
+                    if self.dependency(&name).is_some() {
+                        bail!(
+                            "dependency name {} is used for different sources:\n    {}\n    {}",
+                            name,
+                            self.requirement_path(&name).join(" -> "),
+                            path.join(" -> ")
+                        );
+                    }
+
+                    // targets and links are relative to the nested project, so only the default
+                    // target is used
+                    dependency.target = None;
+                    dependency.links.clear();
+                    self.transitive
+                        .insert(name.clone(), Requirement { dependency, path });
+                    added.push(name);
+                }
+            }
+        }
+
+        Ok(added)
+    }
+
+    pub fn resolve(&self, name: &str) -> Result<ResolvedDependency> {
+        let dependency = self
+            .dependency(name)
+            .ok_or_else(|| anyhow!("unknown dependency {}", name))?;
+
+        self.resolve_dependency(name, dependency, true)
+    }
+
+    /// Like [`Self::resolve`], but without checking that a local path override exists
+    pub(crate) fn resolve_unchecked(&self, name: &str) -> Result<ResolvedDependency> {
+        let dependency = self
+            .dependency(name)
+            .ok_or_else(|| anyhow!("unknown dependency {}", name))?;
+
+        self.resolve_dependency(name, dependency, false)
+    }
+
     pub fn resolve_all(&self) -> Result<HashMap<String, ResolvedDependency>> {
+        self.dependencies()
+            .map(|(key, value)| Ok((key.clone(), self.resolve_dependency(key, value, true)?)))
+            .collect()
+    }
+
+    fn resolve_dependency(
+        &self,
+        key: &str,
+        value: &Dependency,
+        check_paths: bool,
+    ) -> Result<ResolvedDependency> {
         let overrides = self.config_overrides.as_ref().map(|c| &c.dependencies);
-        let map: Result<HashMap<String, ResolvedDependency>> = self
-            .config
-            .dependencies
-            .iter()
-            .map(|(key, value)| {
-                let value = match overrides.and_then(|o| o.get(key)) {
-                    None => match &value.source {
-                        DependencySource::GitRepository { git_repo, git_ref } => {
-                            ResolvedDependency::GitRepository {
-                                url: git_repo.clone(),
-                                fetch_ref: git_ref.to_fetch_ref(),
-                                checkout_ref: git_ref.to_checkout_refspec(),
-                            }
+        let resolved = match overrides.and_then(|o| o.get(key)) {
+            None => match &value.source {
+                DependencySource::GitRepository { git_repo, git_ref } => {
+                    let locked = match self.locked(key, &value.source) {
+                        Some(LockedDependency::GitRepository { commit, .. }) if self.frozen => {
+                            Some(commit.clone())
                         }
-                    },
-                    Some(o) => match o {
-                        DependencyOverride::GitRepository { git_repo, git_ref } => {
-                            ResolvedDependency::GitRepository {
-                                url: git_repo
-                                    .as_ref()
-                                    .or(value.source.git_repo_url())
-                                    .ok_or_else(|| anyhow!("override for {} specifies git ref without repo url but root config does not provide repo url either", key))?
-                                    .clone(),
-                                fetch_ref: git_ref.to_fetch_ref(),
-                                checkout_ref: git_ref.to_checkout_refspec(),
-                            }
+                        _ => None,
+                    };
+
+                    ResolvedDependency::GitRepository {
+                        url: git_repo.clone(),
+                        fetch_ref: git_ref.to_fetch_ref(),
+                        version: git_ref.version().filter(|_| locked.is_none()).cloned(),
+                        containing_ref: git_ref.to_containing_ref(),
+                        checkout_ref: locked.unwrap_or_else(|| git_ref.to_checkout_refspec()),
+                        depth: value.depth,
+                        filter: value.filter.clone(),
+                        paths: value.paths.clone(),
+                        subdir: value.subdir.clone(),
+                    }
+                }
+                DependencySource::Archive {
+                    url,
+                    sha256,
+                    strip_prefix,
+                } => ResolvedDependency::Archive {
+                    url: url.clone(),
+                    sha256: sha256.clone(),
+                    strip_prefix: strip_prefix.clone(),
+                },
+                DependencySource::GithubRelease {
+                    github_repo,
+                    tag,
+                    asset,
+                    sha256,
+                    github_api,
+                } => ResolvedDependency::GithubRelease {
+                    api_url: github_api.clone().unwrap_or_else(github::api_url),
+                    repo: github_repo.clone(),
+                    tag: tag.clone(),
+                    asset: asset.clone(),
+                    sha256: match self.locked(key, &value.source) {
+                        Some(LockedDependency::GithubRelease { sha256, .. })
+                            if self.frozen =>
+                        {
+                            Some(sha256.clone())
                         }
-                        DependencyOverride::LocalPath { local_path } => {
-                            ResolvedDependency::LocalPath {
-                                local_path: {
-                                    local_path.canonicalize().with_context(|| anyhow!("path {} invalid or not supported", local_path.display()))?;
-
-                                    // preserve user's path spec
-                                    local_path.clone()
-                                },
-                            }
+                        _ => sha256.clone(),
+                    },
+                },
+                DependencySource::AzureArtifact {
+                    azure_organization,
+                    azure_project,
+                    feed,
+                    package,
+                    version,
+                    azure_api,
+                } => ResolvedDependency::AzureArtifact {
+                    api_url: azure_api.clone(),
+                    organization: azure_organization.clone(),
+                    project: azure_project.clone(),
+                    feed: feed.clone(),
+                    package: package.clone(),
+                    version: version.clone(),
+                    locked: match self.locked(key, &value.source) {
+                        Some(LockedDependency::AzureArtifact {
+                            resolved_version,
+                            sha256,
+                            ..
+                        }) if self.frozen => {
+                            Some((resolved_version.clone(), sha256.clone()))
                         }
+                        _ => None,
                     },
-                };
+                },
+            },
+            Some(o) => match o {
+                DependencyOverride::GitRepository { git_repo, git_ref } => {
+                    ResolvedDependency::GitRepository {
+                        url: git_repo
+                            .as_ref()
+                            .or(value.source.git_repo_url())
+                            .ok_or_else(|| anyhow!("override for {} specifies git ref without repo url but root config does not provide repo url either", key))?
+                            .clone(),
+                        fetch_ref: git_ref.to_fetch_ref(),
+                        checkout_ref: git_ref.to_checkout_refspec(),
+                        version: git_ref.version().cloned(),
+                        containing_ref: git_ref.to_containing_ref(),
+                        depth: value.depth,
+                        filter: value.filter.clone(),
+                        paths: value.paths.clone(),
+                        subdir: value.subdir.clone(),
+                    }
+                }
+                DependencyOverride::LocalPath { local_path } => {
+                    ResolvedDependency::LocalPath {
+                        local_path: {
+                            if check_paths {
+                                check_local_path(local_path)?;
+                            }
 
-                Ok((key.clone(), value))
-            })
-            .collect();
+                            // preserve user's path spec
+                            local_path.clone()
+                        },
+                        subdir: value.subdir.clone(),
+                    }
+                }
+            },
+        };
 
-        map
+        Ok(resolved)
     }
 }
 
@@ -90,25 +400,54 @@ pub enum ResolvedDependency {
         url: String,
         fetch_ref: String,
         checkout_ref: String,
+        /// Semver requirement selecting the highest of the tags matched by `checkout_ref`
+        version: Option<String>,
+        /// Ref whose tip must contain the checked out commit
+        containing_ref: Option<String>,
+        depth: Option<u32>,
+        filter: Option<String>,
+        /// Directories of a sparse checkout, or empty for a full checkout
+        paths: Vec<String>,
+        subdir: Option<PathBuf>,
+    },
+    Archive {
+        url: String,
+        sha256: String,
+        strip_prefix: Option<PathBuf>,
+    },
+    GithubRelease {
+        api_url: String,
+        repo: String,
+        tag: String,
+        asset: String,
+        sha256: Option<String>,
+    },
+    AzureArtifact {
+        api_url: Option<String>,
+        organization: String,
+        project: Option<String>,
+        feed: String,
+        package: String,
+        version: String,
+        /// Locked version and checksum
+        locked: Option<(String, String)>,
     },
     LocalPath {
         local_path: PathBuf,
+        subdir: Option<PathBuf>,
     },
 }
 
-fn fetch_opts() -> git2::FetchOptions<'static> {
+fn fetch_opts(reporter: &mut dyn Reporter) -> git2::FetchOptions<'_> {
     // Prepare callbacks.
     let mut callbacks = RemoteCallbacks::new();
-    callbacks.credentials(|_url, username_from_url, _allowed_types| {
-        Cred::ssh_key(
-            username_from_url.unwrap_or("git"),
-            None,
-            &dirs::home_dir()
-                .unwrap_or(".".into())
-                .join(".ssh")
-                .join("id_rsa"),
-            None,
-        )
+    let mut credentials = Credentials::new();
+    callbacks.credentials(move |url, username_from_url, allowed_types| {
+        credentials.next(url, username_from_url, allowed_types)
+    });
+    callbacks.transfer_progress(move |stats| {
+        reporter.progress(Progress::transfer(&stats));
+        true
     });
 
     // Prepare fetch options.
@@ -118,8 +457,21 @@ fn fetch_opts() -> git2::FetchOptions<'static> {
     fo
 }
 
-fn clone_repo(url: &str, target_dir: &Path) -> Result<Repository, git2::Error> {
-    let fo = fetch_opts();
+fn checkout_opts(reporter: &mut dyn Reporter) -> CheckoutBuilder<'_> {
+    let mut checkout = CheckoutBuilder::new();
+    checkout.progress(move |_path, completed, total| {
+        reporter.progress(Progress::Checkout { completed, total });
+    });
+
+    checkout
+}
+
+fn clone_repo(
+    url: &str,
+    target_dir: &Path,
+    reporter: &mut dyn Reporter,
+) -> Result<Repository, git2::Error> {
+    let fo = fetch_opts(reporter);
 
     // Prepare builder.
     let mut builder = git2::build::RepoBuilder::new();
@@ -131,9 +483,281 @@ fn clone_repo(url: &str, target_dir: &Path) -> Result<Repository, git2::Error> {
     builder.clone(url, target_dir)
 }
 
+fn fetch(
+    repo: &Repository,
+    url: &str,
+    refspecs: &[&str],
+    partial: Partial,
+    reporter: &mut dyn Reporter,
+) -> Result<()> {
+    if partial.uses_git(repo) {
+        return partial.fetch(repo, url, refspecs);
+    }
+
+    repo.remote_anonymous(url)
+        .context("invalid remote")?
+        .fetch(refspecs, Some(&mut fetch_opts(reporter)), None)
+        .with_context(|| anyhow!("failed to fetch from {}", url))
+}
+
+/// Fetches `fetch_ref` from `url` into the global bare repository.
+///
+/// A commit id is only fetched if it's missing. Servers that don't allow fetching it directly
+/// (or an abbreviated id) get all branches and tags fetched instead, one of which has to contain it.
+fn fetch_ref(
+    repo: &Repository,
+    url: &str,
+    fetch_ref: &str,
+    partial: Partial,
+    reporter: &mut dyn Reporter,
+) -> Result<()> {
+    let is_commit = !fetch_ref.is_empty() && fetch_ref.chars().all(|c| c.is_ascii_hexdigit());
+    let has_commit = || {
+        repo.revparse_single(fetch_ref)
+            .and_then(|o| o.peel_to_commit())
+            .is_ok()
+    };
+    if is_commit && has_commit() {
+        return Ok(());
+    }
+
+    reporter.report(Event::Fetching {
+        url: url.to_string(),
+    });
+    if !is_commit {
+        return fetch(repo, url, &[fetch_ref], partial, reporter);
+    }
+
+    if fetch_ref.len() == 40
+        && fetch(repo, url, &[fetch_ref], partial, reporter).is_ok()
+        && has_commit()
+    {
+        return Ok(());
+    }
+    fetch(
+        repo,
+        url,
+        &[
+            "+refs/heads/*:refs/remotes/origin/*",
+            "+refs/tags/*:refs/tags/*",
+        ],
+        partial,
+        reporter,
+    )?;
+    if !has_commit() {
+        bail!("commit {} not found in {}", fetch_ref, url)
+    }
+
+    Ok(())
+}
+
+/// Fails unless `commit` is the tip of `containing_ref` or one of its ancestors
+fn verify_contained(repo: &Repository, commit: Oid, containing_ref: &str) -> Result<()> {
+    let tip = repo
+        .revparse_single(containing_ref)
+        .and_then(|o| o.peel_to_commit())
+        .with_context(|| anyhow!("cannot resolve {}", containing_ref))?
+        .id();
+    let contained = tip == commit
+        || repo
+            .graph_descendant_of(tip, commit)
+            .context("could not walk history")?;
+    if !contained {
+        bail!(
+            "commit {} is not on {} (at {})",
+            commit,
+            containing_ref.trim_start_matches("refs/remotes/origin/"),
+            tip
+        )
+    }
+
+    Ok(())
+}
+
+/// Clones `url` into a regular repository at `target` with `origin` pointing to `url`.
+///
+/// Objects are copied from the global bare repository, so only missing ones are downloaded,
+/// unless the clone is shallow or partial. Nothing is left behind at `target` on errors.
+pub(crate) fn clone_working_copy(
+    dirs: &Directories,
+    url: &str,
+    git_ref: &GitRef,
+    partial: Partial,
+    target: &Path,
+    offline: bool,
+    reporter: &mut dyn Reporter,
+) -> Result<()> {
+    let existed = target.exists();
+    if existed
+        && target
+            .read_dir()
+            .with_context(|| anyhow!("{} is not a directory", target.display()))?
+            .next()
+            .is_some()
+    {
+        bail!("{} already exists and is not empty", target.display())
+    }
+
+    let global_repo = dirs
+        .global_git_repo(url, offline, partial, reporter)
+        .context("cannot acquire corresponding global git repo")?;
+    if !offline {
+        fetch_ref(
+            &global_repo,
+            url,
+            &git_ref.to_fetch_ref(),
+            partial,
+            reporter,
+        )?;
+    } else if global_repo.is_shallow() || is_partial(&global_repo) {
+        bail!(
+            "cannot clone the shallow or partial cache of {} offline",
+            url
+        )
+    }
+    // the clone gets the same refs, so this fails before anything is created
+    resolve_commit(&global_repo, git_ref)?;
+
+    let result = populate_working_copy(
+        &global_repo,
+        url,
+        git_ref,
+        partial,
+        target,
+        offline,
+        reporter,
+    );
+    if result.is_err() {
+        // so that the clone can simply be retried
+        remove_dir_all::remove_dir_all(target).ok();
+        if existed {
+            fs::create_dir(target).ok();
+        }
+    }
+
+    result
+}
+
+/// The commit `git_ref` refers to in `repo`, which must be on its branch for
+/// [`GitRef::Commit`]
+fn resolve_commit<'r>(repo: &'r Repository, git_ref: &GitRef) -> Result<git2::Commit<'r>> {
+    let mut checkout_ref = git_ref.to_checkout_refspec();
+    if let Some(version) = git_ref.version() {
+        checkout_ref = highest_tag(repo, &checkout_ref, version)?;
+    }
+    let commit = repo
+        .revparse_single(&checkout_ref)
+        .and_then(|o| o.peel_to_commit())
+        .with_context(|| anyhow!("cannot resolve {}", checkout_ref))?;
+    if let Some(containing_ref) = git_ref.to_containing_ref() {
+        verify_contained(repo, commit.id(), &containing_ref)?;
+    }
+
+    Ok(commit)
+}
+
+fn populate_working_copy(
+    global_repo: &Repository,
+    url: &str,
+    git_ref: &GitRef,
+    partial: Partial,
+    target: &Path,
+    offline: bool,
+    reporter: &mut dyn Reporter,
+) -> Result<()> {
+    // libgit2 can't copy objects from a shallow or partial repo
+    let repo = if !offline && partial.uses_git(global_repo) {
+        reporter.report(Event::Cloning {
+            url: url.to_string(),
+            dir: target.to_path_buf(),
+        });
+        let repo = partial.clone(url, target, false)?;
+        fetch_ref(&repo, url, &git_ref.to_fetch_ref(), partial, reporter)?;
+
+        repo
+    } else {
+        let repo = Repository::init(target)
+            .with_context(|| anyhow!("could not create repo at {}", target.display()))?;
+        repo.remote("origin", url)
+            .context("could not add origin remote")?;
+
+        let global_git_dir = global_repo
+            .path()
+            .to_str()
+            .context("unsupported global git dir")?;
+        fetch(
+            &repo,
+            global_git_dir,
+            &[
+                "+refs/remotes/origin/*:refs/remotes/origin/*",
+                "+refs/tags/*:refs/tags/*",
+            ],
+            Partial::default(),
+            reporter,
+        )?;
+        if !offline {
+            repo.find_remote("origin")
+                .context("invalid remote")?
+                .fetch(&[] as &[&str], Some(&mut fetch_opts(reporter)), None)
+                .with_context(|| anyhow!("failed to fetch from {}", url))?;
+        }
+
+        repo
+    };
+
+    let commit = resolve_commit(&repo, git_ref)?;
+    match git_ref {
+        GitRef::Branch { branch } => {
+            // a shallow or partial clone already has the default branch
+            repo.find_branch(branch, BranchType::Local)
+                .or_else(|_| repo.branch(branch, &commit, false))
+                .and_then(|mut b| b.set_upstream(Some(&format!("origin/{}", branch))))
+                .context("could not create local branch")?;
+            repo.set_head(&format!("refs/heads/{}", branch))
+        }
+        _ => repo.set_head_detached(commit.id()),
+    }
+    .context("cannot switch to ref")?;
+    if !offline {
+        fetch_missing_blobs(&repo, commit.id())?;
+    }
+    repo.checkout_head(Some(checkout_opts(reporter).force()))
+        .context("could not checkout HEAD")
+}
+
+/// Returns the highest of the tags matching `glob` that satisfies the semver requirement
+/// `version`, ignoring prefixes like the `v` in `v1.3.0`
+pub(crate) fn highest_tag(repo: &Repository, glob: &str, version: &str) -> Result<String> {
+    let req = VersionReq::parse(version)
+        .with_context(|| anyhow!("invalid version requirement {}", version))?;
+
+    let mut highest: Option<(Version, String)> = None;
+    let mut references = repo.references_glob(glob).context("could not list tags")?;
+    for name in references.names() {
+        let name = name.context("could not read tag")?;
+        let tag = name.strip_prefix("refs/tags/").unwrap_or(name);
+        let parsed = match Version::parse(tag.trim_start_matches(|c: char| !c.is_ascii_digit())) {
+            Ok(parsed) if req.matches(&parsed) => parsed,
+            _ => continue,
+        };
+        if highest.as_ref().is_none_or(|(v, _)| &parsed > v) {
+            highest = Some((parsed, name.to_string()));
+        }
+    }
+
+    highest
+        .map(|(_, name)| name)
+        .ok_or_else(|| anyhow!("no tag matches version {}", version))
+}
+
 fn normalize_url_for_dir(url: &str) -> Result<PathBuf> {
     let url = Url::from_str(url).context("could not parse url")?;
-    let domain = url.domain().context("missing domain")?;
+    let domain = match url.domain() {
+        Some(domain) => domain,
+        // local repositories, e.g. for tests
+        None if url.scheme() == "file" => "localhost",
+        None => bail!("missing domain"),
+    };
     let mut path: PathBuf = domain.into();
     for segment in url.path_segments().into_iter().flatten() {
         path.push(segment);
@@ -143,21 +767,165 @@ fn normalize_url_for_dir(url: &str) -> Result<PathBuf> {
     Ok(path)
 }
 
-/// Patches (creates or updates) a `symlink_dir` to point to `existing_dir`
-fn safe_symlink_dir(symlink_dir: &Path, existing_dir: &Path) -> Result<()> {
-    if std::fs::symlink_metadata(symlink_dir)
+/// Identifies the source of a dependency independent of the requested version
+fn source_key(source: &DependencySource) -> String {
+    match source {
+        DependencySource::GitRepository { git_repo, .. } => normalize_url_for_dir(git_repo)
+            .map(|p| p.display().to_string().replace('\\', "/"))
+            .unwrap_or_else(|_| git_repo.clone()),
+        DependencySource::Archive { url, .. } => url.clone(),
+        DependencySource::GithubRelease {
+            github_repo, asset, ..
+        } => format!("github release {} {}", github_repo, asset),
+        DependencySource::AzureArtifact {
+            azure_organization,
+            feed,
+            package,
+            ..
+        } => format!("azure artifact {}/{}/{}", azure_organization, feed, package),
+    }
+}
+
+fn describe_requirement(source: &DependencySource) -> String {
+    match source {
+        DependencySource::GitRepository { git_ref, .. } => match git_ref {
+            GitRef::Branch { branch } => format!("branch {}", branch),
+            GitRef::Tag { tag } => format!("tag {}", tag),
+            GitRef::Commit { branch, commit } => format!("commit {} on {}", commit, branch),
+            GitRef::CommitOnly { commit } => format!("commit {}", commit),
+            GitRef::Version { version } => format!("version {}", version),
+        },
+        other => format!("{:?}", other),
+    }
+}
+
+/// Returns whether both urls refer to the same repository, ignoring e.g. a `.git` suffix
+pub(crate) fn same_repo_url(a: &str, b: &str) -> bool {
+    match (normalize_url_for_dir(a), normalize_url_for_dir(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a.trim_end_matches(".git") == b.trim_end_matches(".git"),
+    }
+}
+
+/// Checks lexically whether `path` (absolute or relative to `root`) stays inside of `root`
+fn is_inside(root: &Path, path: &Path) -> bool {
+    let path = if path.is_absolute() {
+        match path.strip_prefix(root) {
+            Ok(path) => path,
+            Err(_) => return false,
+        }
+    } else {
+        path
+    };
+
+    let mut depth = 0;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            _ => return false,
+        }
+    }
+
+    true
+}
+
+/// Path of `subdir` inside of `dir`, or `dir` itself without `subdir`
+pub(crate) fn subdir_path(dir: &Path, subdir: Option<&Path>) -> Result<PathBuf> {
+    match subdir {
+        Some(subdir) if subdir.is_absolute() || !is_inside(dir, subdir) => bail!(
+            "subdir {} must be a relative path inside of the dependency",
+            subdir.display()
+        ),
+        Some(subdir) => Ok(dir.join(subdir)),
+        None => Ok(dir.to_path_buf()),
+    }
+}
+
+/// Checks that a path used for a `LocalPath` override exists
+pub fn check_local_path(local_path: &Path) -> Result<()> {
+    local_path
+        .canonicalize()
+        .with_context(|| anyhow!("path {} invalid or not supported", local_path.display()))?;
+
+    Ok(())
+}
+
+/// Returns the url of a remote of the repository at `path` if no remote refers to `url`
+pub(crate) fn mismatched_remote(path: &Path, url: &str) -> Option<String> {
+    let repo = Repository::open(path).ok()?;
+    let remotes = repo.remotes().ok()?;
+    let urls: Vec<String> = remotes
+        .iter()
+        .flatten()
+        .filter_map(|name| repo.find_remote(name).ok()?.url().map(ToString::to_string))
+        .collect();
+
+    if urls.iter().any(|remote_url| same_repo_url(remote_url, url)) {
+        None
+    } else {
+        let origin = repo.find_remote("origin").ok();
+        origin
+            .as_ref()
+            .and_then(|o| o.url())
+            .map(ToString::to_string)
+            .or_else(|| urls.into_iter().next())
+    }
+}
+
+/// Fails unless `path` is a repository with commits and a remote pointing to `url`, which
+/// can be used instead of a fresh clone
+pub(crate) fn check_working_copy(path: &Path, url: &str) -> Result<()> {
+    let repo = Repository::open(path)
+        .with_context(|| anyhow!("{} is not a git repository", path.display()))?;
+    repo.head()
+        .and_then(|h| h.peel_to_commit())
+        .with_context(|| anyhow!("{} has no commits", path.display()))?;
+    if repo.remotes().context("could not list remotes")?.is_empty() {
+        bail!("{} has no remote", path.display())
+    }
+    if let Some(remote) = mismatched_remote(path, url) {
+        bail!(
+            "{} has remote {}, which does not match the config",
+            path.display(),
+            remote
+        )
+    }
+
+    Ok(())
+}
+
+fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path)
         .map(|m| m.file_type().is_symlink())
         .unwrap_or(false)
-    {
-        symlink::remove_symlink_dir(symlink_dir)
-            .with_context(|| anyhow!("could not remove symlink {}", symlink_dir.display()))?;
-    } else if symlink_dir.exists() {
+}
+
+/// Fails if anything but a symlink is in the way of `symlink_dir`
+fn check_symlink_dir(symlink_dir: &Path) -> Result<()> {
+    if !is_symlink(symlink_dir) && symlink_dir.exists() {
         bail!(
             "dir {} already exists but is not a symlink",
             symlink_dir.display()
         )
     }
 
+    Ok(())
+}
+
+/// Patches (creates or updates) a `symlink_dir` to point to `existing_dir`, creating its
+/// parent directories if necessary
+fn safe_symlink_dir(symlink_dir: &Path, existing_dir: &Path) -> Result<()> {
+    check_symlink_dir(symlink_dir)?;
+    if is_symlink(symlink_dir) {
+        symlink::remove_symlink_dir(symlink_dir)
+            .with_context(|| anyhow!("could not remove symlink {}", symlink_dir.display()))?;
+    } else if let Some(parent) = symlink_dir.parent() {
+        create_dir_all(parent)
+            .with_context(|| anyhow!("could not create directory {}", parent.display()))?;
+    }
+
     let existing_dir = existing_dir
         .canonicalize()
         .with_context(|| anyhow!("path {} invalid or unsupported", existing_dir.display()))?;
@@ -173,9 +941,21 @@ pub struct DependencyDirs<'a> {
     pub local_git_worktree: &'a Path,
 }
 
-impl<'a> DependencyDirs<'a> {
-    fn global_git_repo(&self, url: &str) -> Result<Repository> {
-        let global_git_dir = self.base.global_git_repos.join(normalize_url_for_dir(url)?);
+impl Directories {
+    /// Location of the global bare repository for `url`
+    pub fn global_git_dir(&self, url: &str) -> Result<PathBuf> {
+        Ok(self.global_git_repos.join(normalize_url_for_dir(url)?))
+    }
+
+    /// Opens the global bare repository for `url`, cloning it first if necessary and not `offline`
+    pub(crate) fn global_git_repo(
+        &self,
+        url: &str,
+        offline: bool,
+        partial: Partial,
+        reporter: &mut dyn Reporter,
+    ) -> Result<Repository> {
+        let global_git_dir = self.global_git_dir(url)?;
         let global_git_dir = &global_git_dir;
         {
             let parent_git_dir = global_git_dir.parent().unwrap();
@@ -189,9 +969,18 @@ impl<'a> DependencyDirs<'a> {
 
         let repo = if global_git_dir.exists() {
             Repository::open(global_git_dir).context("could not open repo")?
+        } else if offline {
+            bail!("{} is not cached", url)
         } else {
-            println!("  cloning into {}...", global_git_dir.display());
-            clone_repo(url, global_git_dir).context("could not clone repo")?
+            reporter.report(Event::Cloning {
+                url: url.to_string(),
+                dir: global_git_dir.clone(),
+            });
+            if partial.is_full() {
+                clone_repo(url, global_git_dir, reporter).context("could not clone repo")?
+            } else {
+                partial.clone(url, global_git_dir, true)?
+            }
         };
 
         if repo.is_worktree() || !repo.is_bare() {
@@ -200,11 +989,36 @@ impl<'a> DependencyDirs<'a> {
                 global_git_dir.display()
             )
         }
+        // usage only guides `cache gc`, so failing to record it is no reason to fail
+        record_usage(&repo, url).ok();
 
         Ok(repo)
     }
+}
+
+impl<'a> DependencyDirs<'a> {
+    fn global_git_repo(
+        &self,
+        url: &str,
+        offline: bool,
+        partial: Partial,
+        reporter: &mut dyn Reporter,
+    ) -> Result<Repository> {
+        self.base.global_git_repo(url, offline, partial, reporter)
+    }
 
-    fn create_update_worktree(&self, global_repo: &Repository) -> Result<Repository> {
+    /// Opens the worktree of `global_repo`, replacing whatever else is in its place.
+    ///
+    /// A new worktree is created at `commit` if it needs the `git` executable anyway.
+    fn create_update_worktree(
+        &self,
+        global_repo: &Repository,
+        commit: Oid,
+        paths: &[String],
+        partial: Partial,
+        options: &AcquireOptions,
+        reporter: &mut dyn Reporter,
+    ) -> Result<Repository> {
         let git_wt_dir = self.local_git_worktree;
 
         let worktree_name = if git_wt_dir.exists() {
@@ -239,13 +1053,27 @@ impl<'a> DependencyDirs<'a> {
             if git_wt_dir.exists() {
                 // this might be a repo, but not a worktreee of the correct repo
                 match Repository::open(git_wt_dir).context("could not open repo") {
-                    Ok(repo) => {
-                        println!("  replacing worktree due to repo mismatch");
-
+                    Ok(mut repo) => {
                         if !repo.is_worktree() {
                             bail!("local git dirs must be worktrees, but found standalone repo")
                         }
 
+                        // removing the worktree discards untracked files as well
+                        let dirty_files = dirty_files(&repo, true)?;
+                        if !dirty_files.is_empty() {
+                            handle_dirty_files(
+                                &mut repo,
+                                &dirty_files,
+                                true,
+                                "pkgstrap: before replacing the worktree",
+                                options,
+                                reporter,
+                            )?;
+                        }
+                        reporter.report(Event::WorktreeReplaced {
+                            dir: git_wt_dir.to_path_buf(),
+                        });
+
                         let worktree = Worktree::open_from_repository(&repo).unwrap();
                         worktree
                             .prune(Some(
@@ -254,30 +1082,39 @@ impl<'a> DependencyDirs<'a> {
                             .context("failed to remove outdated worktree")?;
                     }
                     _ => {
-                        println!("  removing leftover git worktree files");
+                        reporter.report(Event::LeftoverWorktreeRemoved {
+                            dir: git_wt_dir.to_path_buf(),
+                        });
                         remove_dir_all::remove_dir_all(git_wt_dir)
                             .context("failed to remove leftover git worktree files")?;
                     }
                 }
             }
 
-            let worktree_name =
-                format!("todo-{}", git_wt_dir.file_name().unwrap().to_str().unwrap());
+            if !paths.is_empty() || partial.uses_git(global_repo) {
+                return add_worktree(global_repo, git_wt_dir, commit, paths);
+            }
+
+            let base_name = format!("todo-{}", git_wt_dir.file_name().unwrap().to_str().unwrap());
+            // other projects may have a dependency of the same name
+            let worktree_name = (0..)
+                .map(|i| match i {
+                    0 => base_name.clone(),
+                    i => format!("{}-{}", base_name, i),
+                })
+                .find(|name| {
+                    !global_repo
+                        .find_worktree(name)
+                        .map(|w| w.path().exists())
+                        .unwrap_or(false)
+                })
+                .expect("unbounded range");
 
             let raw_worktree_link_dir = global_repo.path().join("worktrees").join(&worktree_name);
             if raw_worktree_link_dir.exists() {
-                if global_repo
-                    .find_worktree(&worktree_name)
-                    .map(|w| w.path().exists())
-                    .unwrap_or(false)
-                {
-                    bail!(
-                        "worktree name conflict; worktree called {} already exists",
-                        worktree_name
-                    )
-                }
-
-                println!("  removing existing invalid worktree from repo");
+                reporter.report(Event::InvalidWorktreeRemoved {
+                    name: worktree_name.clone(),
+                });
                 remove_dir_all::remove_dir_all(&raw_worktree_link_dir)
                     .context("failed to remove worktree metadata from root repo")?;
             }
@@ -287,45 +1124,375 @@ impl<'a> DependencyDirs<'a> {
             }
 
             global_repo
-                .worktree(&worktree_name, &git_wt_dir, None)
+                .worktree(&worktree_name, git_wt_dir, None)
                 .context("failed to create worktree")?;
 
             Repository::open(git_wt_dir)
         };
-        repo.context("could not open local worktree")
+        let repo = repo.context("could not open local worktree")?;
+        set_sparse_paths(&repo, paths)?;
+
+        Ok(repo)
+    }
+}
+
+/// Removes `link` if it is a symlink, leaving anything else alone
+pub fn remove_symlink(link: &Path, reporter: &mut dyn Reporter) -> Result<()> {
+    match fs::symlink_metadata(link) {
+        Ok(metadata) if metadata.file_type().is_symlink() => {
+            symlink::remove_symlink_dir(link)
+                .with_context(|| anyhow!("could not remove symlink {}", link.display()))?;
+            reporter.report(Event::LinkRemoved {
+                link: link.to_path_buf(),
+            });
+
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Removes the worktree at `dir` and its registration and branch in the global bare repository.
+///
+/// Worktrees with uncommitted changes or untracked files are kept unless `force` is set.
+pub fn remove_worktree(dir: &Path, force: bool, reporter: &mut dyn Reporter) -> Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+
+    let repo = Repository::open(dir)
+        .ok()
+        .filter(|r| r.is_worktree())
+        .ok_or_else(|| anyhow!("{} is not a git worktree", dir.display()))?;
+    // removing the worktree discards untracked files as well
+    let dirty_files = dirty_files(&repo, true)?;
+    if !dirty_files.is_empty() && !force {
+        reporter.report(Event::WorktreeKept {
+            dir: dir.to_path_buf(),
+            files: dirty_files.len(),
+        });
+
+        return Ok(());
+    }
+
+    let worktree = Worktree::open_from_repository(&repo).context("could not open worktree")?;
+    // the git dir of a worktree is `<global repo>/worktrees/<name>`
+    let global_repo = repo
+        .path()
+        .ancestors()
+        .nth(2)
+        .and_then(|p| Repository::open_bare(p).ok())
+        .context("could not open global repo")?;
+    drop(repo);
+
+    worktree
+        .prune(Some(
+            WorktreePruneOptions::new().valid(true).working_tree(true),
+        ))
+        .with_context(|| anyhow!("could not remove worktree {}", dir.display()))?;
+    if let Some(name) = worktree.name() {
+        if let Ok(mut branch) = global_repo.find_branch(name, BranchType::Local) {
+            branch
+                .delete()
+                .context("could not delete worktree branch")?;
+        }
+    }
+    reporter.report(Event::WorktreeRemoved {
+        dir: dir.to_path_buf(),
+    });
+
+    Ok(())
+}
+
+/// Prunes worktrees of the global bare repository at `global_git_dir` whose directories
+/// no longer exist, along with their branches
+pub fn prune_dangling_worktrees(global_git_dir: &Path, reporter: &mut dyn Reporter) -> Result<()> {
+    let global_repo = Repository::open_bare(global_git_dir)
+        .with_context(|| anyhow!("could not open {}", global_git_dir.display()))?;
+    let names = global_repo.worktrees().context("cannot query worktrees")?;
+
+    for name in names.iter().flatten() {
+        let worktree = global_repo
+            .find_worktree(name)
+            .context("could not open worktree")?;
+        if worktree.validate().is_ok() {
+            continue;
+        }
+
+        worktree
+            .prune(None)
+            .with_context(|| anyhow!("could not prune worktree {}", name))?;
+        if let Ok(mut branch) = global_repo.find_branch(name, BranchType::Local) {
+            branch
+                .delete()
+                .context("could not delete worktree branch")?;
+        }
+        reporter.report(Event::WorktreeRemoved {
+            dir: worktree.path().to_path_buf(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Lists files with uncommitted changes to tracked files, which a forced checkout would discard,
+/// and with `untracked`, the untracked files that removing the worktree would discard as well
+pub(crate) fn dirty_files(repo: &Repository, untracked: bool) -> Result<Vec<String>> {
+    let statuses = repo
+        .statuses(Some(
+            StatusOptions::new()
+                .include_untracked(untracked)
+                .recurse_untracked_dirs(untracked)
+                .include_ignored(false),
+        ))
+        .context("could not query worktree status")?;
+    let index = repo.index().context("could not read index")?;
+    // libgit2 reports the files left out by a sparse checkout as deleted
+    let skips_worktree = |path: &str| {
+        index
+            .get_path(Path::new(path), 0)
+            .map(|e| IndexEntryExtendedFlag::from_bits_truncate(e.flags_extended))
+            .map(|f| f.is_skip_worktree())
+            .unwrap_or(false)
+    };
+
+    Ok(statuses
+        .iter()
+        .filter(|s| s.status() != Status::CURRENT)
+        .filter_map(|s| s.path().map(ToString::to_string))
+        .filter(|path| !skips_worktree(path))
+        .collect())
+}
+
+/// What to do with uncommitted changes in a worktree that is about to be updated
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DirtyWorktree {
+    /// Fail with a list of the modified files
+    Abort,
+    /// Stash the changes before switching
+    Stash,
+    /// Discard the changes
+    Force,
+}
+
+#[derive(Clone, Debug)]
+pub struct AcquireOptions {
+    pub on_dirty: DirtyWorktree,
+    /// Whether uncommitted changes are an error even if HEAD does not change
+    pub pedantic: bool,
+    /// Skips all fetches and downloads, only using the global git repos and artifact cache
+    pub offline: bool,
+}
+
+/// Applies `options.on_dirty` to the uncommitted `dirty_files` of the worktree `repo` before
+/// they are overwritten, stashing untracked files too if `untracked` is set
+fn handle_dirty_files(
+    repo: &mut Repository,
+    dirty_files: &[String],
+    untracked: bool,
+    stash_message: &str,
+    options: &AcquireOptions,
+    reporter: &mut dyn Reporter,
+) -> Result<()> {
+    let dir = repo.workdir().unwrap_or_else(|| repo.path()).to_path_buf();
+    match options.on_dirty {
+        DirtyWorktree::Abort => bail!(
+            "worktree {} has uncommitted changes:\n    {}",
+            dir.display(),
+            dirty_files.join("\n    ")
+        ),
+        _ if options.pedantic => {
+            bail!("worktree {} has uncommitted changes", dir.display())
+        }
+        DirtyWorktree::Stash => {
+            stash_changes(repo, stash_message, untracked)?;
+            reporter.report(Event::ChangesStashed {
+                files: dirty_files.len(),
+            });
+        }
+        DirtyWorktree::Force => {
+            reporter.report(Event::ChangesDiscarded {
+                files: dirty_files.len(),
+            });
+        }
     }
+
+    Ok(())
+}
+
+fn stash_changes(repo: &mut Repository, message: &str, untracked: bool) -> Result<()> {
+    let signature = repo
+        .signature()
+        .or_else(|_| Signature::now("pkgstrap", "pkgstrap@localhost"))
+        .context("could not create signature")?;
+    let flags = if untracked {
+        StashFlags::INCLUDE_UNTRACKED
+    } else {
+        StashFlags::DEFAULT
+    };
+    repo.stash_save(&signature, message, Some(flags))
+        .context("could not stash changes")?;
+
+    Ok(())
 }
 
 impl ResolvedDependency {
-    pub fn acquire(&self, dirs: DependencyDirs) -> Result<()> {
+    /// Keys of the global caches this dependency writes to, i.e. its bare repository, or the
+    /// download and checksum of an artifact.
+    ///
+    /// Dependencies sharing a key must not be acquired concurrently.
+    pub fn cache_keys(&self, dirs: &Directories) -> Result<Vec<String>> {
+        let sha256 = |sha256: &str| format!("sha256:{}", sha256.to_ascii_lowercase());
+
+        Ok(match self {
+            ResolvedDependency::GitRepository { url, .. } => {
+                vec![format!("git:{}", dirs.global_git_dir(url)?.display())]
+            }
+            ResolvedDependency::Archive {
+                url, sha256: sum, ..
+            } => vec![format!("url:{}", url), sha256(sum)],
+            ResolvedDependency::GithubRelease {
+                repo,
+                tag,
+                asset,
+                sha256: sum,
+                ..
+            } => std::iter::once(format!("github:{}/{}/{}", repo, tag, asset))
+                .chain(sum.as_deref().map(sha256))
+                .collect(),
+            ResolvedDependency::AzureArtifact {
+                organization,
+                feed,
+                package,
+                locked,
+                ..
+            } => std::iter::once(format!("azure:{}/{}/{}", organization, feed, package))
+                .chain(locked.as_ref().map(|(_, sum)| sha256(sum)))
+                .collect(),
+            ResolvedDependency::LocalPath { .. } => vec![],
+        })
+    }
+
+    /// Returns what the dependency was pinned to, if it can be locked
+    pub fn acquire(
+        &self,
+        dirs: DependencyDirs,
+        options: &AcquireOptions,
+        reporter: &mut dyn Reporter,
+    ) -> Result<Option<Pinned>> {
         let target_dir = dirs.std_target_dir;
+        // rather than after checking out or downloading the dependency
+        for dir in &dirs.in_tree_target_dirs {
+            check_symlink_dir(dir)
+                .with_context(|| anyhow!("could not create link {}", dir.display()))?;
+        }
 
-        match self {
+        let commit = match self {
             ResolvedDependency::GitRepository {
                 url,
                 fetch_ref,
                 checkout_ref,
+                version,
+                containing_ref,
+                depth,
+                filter,
+                paths,
+                subdir,
             } => {
                 let git_wt_dir = dirs.local_git_worktree;
+                let partial = Partial {
+                    depth: *depth,
+                    filter: filter.as_deref(),
+                };
 
                 let global_repo = dirs
-                    .global_git_repo(url)
+                    .global_git_repo(url, options.offline, partial, reporter)
                     .context("cannot acquire corresponding global git repo")?;
-                global_repo
-                    .remote_anonymous(url)
-                    .context("invalid remote")?
-                    .fetch(&[fetch_ref], Some(&mut fetch_opts()), None)
-                    .with_context(|| anyhow!("failed to fetch from {}", url))?;
-                let repo = dirs.create_update_worktree(&global_repo)?;
-
-                let head_ref = repo.head().expect("could not get HEAD").resolve().unwrap();
-                let latest_commit = head_ref.peel_to_commit().unwrap();
-                let prev_latest_commit = latest_commit.id();
-
-                repo.set_head(&checkout_ref)
-                    .context("cannot switch to ref")?;
-                repo.checkout_head(Some(CheckoutBuilder::new().force()))
-                    .context("could not checkout HEAD")?;
+                if !options.offline {
+                    self::fetch_ref(&global_repo, url, fetch_ref, partial, reporter)?;
+                }
+
+                // worktrees share all refs but HEAD with the global repo
+                let tag = match version {
+                    Some(version) => {
+                        let tag = highest_tag(&global_repo, checkout_ref, version)?;
+                        reporter.report(Event::TagSelected {
+                            tag: tag.trim_start_matches("refs/tags/").to_string(),
+                        });
+                        Some(tag)
+                    }
+                    None => None,
+                };
+                let checkout_ref = tag.as_deref().unwrap_or(checkout_ref);
+                let checkout_commit = global_repo
+                    .revparse_single(checkout_ref)
+                    .and_then(|o| o.peel_to_commit())
+                    .with_context(|| {
+                        if options.offline {
+                            anyhow!("{} of {} is not cached", checkout_ref, url)
+                        } else {
+                            anyhow!("cannot resolve {}", checkout_ref)
+                        }
+                    })?
+                    .id();
+                if let Some(containing_ref) = containing_ref {
+                    verify_contained(&global_repo, checkout_commit, containing_ref)?;
+                }
+
+                let mut repo = dirs.create_update_worktree(
+                    &global_repo,
+                    checkout_commit,
+                    paths,
+                    partial,
+                    options,
+                    reporter,
+                )?;
+                let prev_latest_commit = {
+                    let head_ref = repo.head().expect("could not get HEAD").resolve().unwrap();
+                    head_ref.peel_to_commit().unwrap().id()
+                };
+
+                let dirty_files = dirty_files(&repo, false)?;
+                let head_changes = checkout_commit != prev_latest_commit;
+                if !dirty_files.is_empty() && (head_changes || options.pedantic) {
+                    handle_dirty_files(
+                        &mut repo,
+                        &dirty_files,
+                        false,
+                        &format!("pkgstrap: before switching to {}", checkout_commit),
+                        options,
+                        reporter,
+                    )?;
+                }
+
+                if !paths.is_empty() && (dirty_files.is_empty() || head_changes) {
+                    // libgit2 ignores sparse checkouts, and git fetches missing blobs itself
+                    checkout_sparse(
+                        &repo,
+                        checkout_commit,
+                        options.on_dirty == DirtyWorktree::Force,
+                    )?;
+                } else if dirty_files.is_empty() || head_changes {
+                    if !options.offline {
+                        fetch_missing_blobs(&global_repo, checkout_commit)?;
+                    }
+                    let mut checkout = checkout_opts(reporter);
+                    if options.on_dirty == DirtyWorktree::Force {
+                        checkout.force();
+                    } else {
+                        checkout.safe();
+                    }
+
+                    // checkout before switching HEAD, so the old HEAD is the baseline
+                    let tree = repo
+                        .find_commit(checkout_commit)
+                        .and_then(|c| c.tree())
+                        .context("unexpected error while resolving tree")?;
+                    repo.checkout_tree(tree.as_object(), Some(&mut checkout))
+                        .context("could not checkout tree")?;
+                    repo.set_head_detached(checkout_commit)
+                        .context("cannot switch to ref")?;
+                }
                 let head_ref = repo
                     .head()
                     .context("unexpected error while resolving HEAD")?;
@@ -333,36 +1500,904 @@ impl ResolvedDependency {
                     .peel_to_commit()
                     .context("unexpected error while resolving HEAD")?;
 
-                safe_symlink_dir(target_dir, git_wt_dir)?;
+                let linked_dir = subdir_path(git_wt_dir, subdir.as_deref())?;
+                match subdir {
+                    Some(subdir) if !linked_dir.is_dir() => bail!(
+                        "subdir {} does not exist at commit {}",
+                        subdir.display(),
+                        latest_commit.id()
+                    ),
+                    _ => {}
+                }
+                safe_symlink_dir(target_dir, &linked_dir)?;
 
                 if prev_latest_commit == latest_commit.id() {
-                    println!("  at commit {:?}", prev_latest_commit);
+                    reporter.report(Event::AtCommit {
+                        commit: prev_latest_commit.to_string(),
+                    });
                 } else {
-                    println!(
-                        "  updated HEAD to commit {:?} (from {:?})",
-                        latest_commit.id(),
-                        prev_latest_commit
-                    );
+                    reporter.report(Event::HeadUpdated {
+                        from: prev_latest_commit.to_string(),
+                        to: latest_commit.id().to_string(),
+                    });
                 }
+
+                Some(match tag {
+                    Some(tag) => Pinned::Tag {
+                        tag: tag.trim_start_matches("refs/tags/").to_string(),
+                        commit: latest_commit.id(),
+                    },
+                    None => Pinned::Commit(latest_commit.id()),
+                })
             }
-            ResolvedDependency::LocalPath { local_path } => {
-                safe_symlink_dir(target_dir, local_path)?;
+            ResolvedDependency::Archive {
+                url,
+                sha256,
+                strip_prefix,
+            } => {
+                let mut dir = if options.offline {
+                    dirs.base
+                        .cached_artifact(sha256)?
+                        .ok_or_else(|| anyhow!("archive {} is not cached", url))?
+                } else {
+                    dirs.base
+                        .archive(url, sha256, reporter)
+                        .context("cannot acquire archive")?
+                };
+                if let Some(strip_prefix) = strip_prefix {
+                    dir.push(strip_prefix);
+                    if !dir.is_dir() {
+                        bail!("archive does not contain {}", strip_prefix.display())
+                    }
+                }
+
+                safe_symlink_dir(target_dir, &dir)?;
 
-                println!("  linked to {}", local_path.display());
+                reporter.report(Event::LinkedArchive {
+                    sha256: sha256.clone(),
+                });
+
+                None
             }
-        }
+            ResolvedDependency::GithubRelease {
+                api_url,
+                repo,
+                tag,
+                asset,
+                sha256,
+            } => {
+                let cached = match sha256 {
+                    Some(sha256) => dirs.base.cached_artifact(sha256)?,
+                    None => None,
+                };
+                let (dir, sha256) = match (cached, sha256) {
+                    (Some(dir), Some(sha256)) => (dir, sha256.to_ascii_lowercase()),
+                    _ if options.offline => {
+                        bail!(
+                            "asset {} of release {} of {} is not cached",
+                            asset,
+                            tag,
+                            repo
+                        )
+                    }
+                    _ => {
+                        let asset = github::find_asset(api_url, repo, tag, asset)?;
+                        dirs.base
+                            .artifact(
+                                &asset.url,
+                                &github::headers("application/octet-stream"),
+                                Some(&asset.name),
+                                sha256.as_deref(),
+                                reporter,
+                            )
+                            .with_context(|| anyhow!("cannot acquire asset {}", asset.name))?
+                    }
+                };
+
+                safe_symlink_dir(target_dir, &dir)?;
+
+                reporter.report(Event::LinkedReleaseAsset {
+                    sha256: sha256.clone(),
+                });
+
+                Some(Pinned::Sha256(sha256))
+            }
+            ResolvedDependency::AzureArtifact {
+                api_url,
+                organization,
+                project,
+                feed,
+                package,
+                version,
+                locked,
+            } => {
+                let cached = match locked {
+                    Some((_, sha256)) => dirs.base.cached_artifact(sha256)?,
+                    None => None,
+                };
+                let (version, dir, sha256) = match (cached, locked) {
+                    (Some(dir), Some((version, sha256))) => {
+                        (version.clone(), dir, sha256.to_ascii_lowercase())
+                    }
+                    _ if options.offline => {
+                        bail!("package {} {} is not cached", package, version)
+                    }
+                    _ => {
+                        let package = azure::Package {
+                            api_url: api_url.as_deref(),
+                            organization,
+                            project: project.as_deref(),
+                            feed,
+                            name: package,
+                            az_cli: None,
+                        };
+                        let (version, sha256) = match locked {
+                            Some((version, sha256)) => (version.clone(), Some(sha256.as_str())),
+                            None => (package.resolve_version(version)?, None),
+                        };
+                        let key = format!("{}/{}/{} {}", organization, feed, package.name, version);
+                        let (dir, sha256) = dirs
+                            .base
+                            .artifact_dir(&key, sha256, |dest| {
+                                reporter.report(Event::Downloading { url: key.clone() });
+                                package.download(&version, dest)
+                            })
+                            .with_context(|| {
+                                anyhow!("cannot acquire package {} {}", package.name, version)
+                            })?;
+
+                        (version, dir, sha256)
+                    }
+                };
+
+                safe_symlink_dir(target_dir, &dir)?;
+
+                reporter.report(Event::LinkedPackage {
+                    version: version.clone(),
+                });
+
+                Some(Pinned::Package { version, sha256 })
+            }
+            ResolvedDependency::LocalPath { local_path, subdir } => {
+                let linked_dir = subdir_path(local_path, subdir.as_deref())?;
+                match subdir {
+                    Some(subdir) if !linked_dir.is_dir() => bail!(
+                        "subdir {} does not exist in {}",
+                        subdir.display(),
+                        local_path.display()
+                    ),
+                    _ => {}
+                }
+                safe_symlink_dir(target_dir, &linked_dir)?;
+
+                reporter.report(Event::LinkedLocalPath {
+                    local_path: local_path.clone(),
+                });
+
+                None
+            }
+        };
 
         for dir in dirs.in_tree_target_dirs {
-            safe_symlink_dir(dir, target_dir).context("could not create additional link")?;
+            safe_symlink_dir(dir, target_dir)
+                .with_context(|| anyhow!("could not create link {}", dir.display()))?;
         }
 
-        Ok(())
+        Ok(commit)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::resolved::normalize_url_for_dir;
+    use std::{
+        collections::HashMap,
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use git2::{ObjectType, Oid, Repository, RepositoryInitOptions, Signature};
+    use tempfile::TempDir;
+
+    use crate::{
+        resolved::{
+            highest_tag, is_inside, normalize_url_for_dir, remove_worktree, same_repo_url,
+            subdir_path, verify_contained,
+        },
+        test_util::{directories, git_dependency},
+        AcquireOptions, Config, Dependency, DependencyDirs, DependencySource, Directories,
+        DirtyWorktree, GitRef, LockFile, LockedDependency, Pinned, ResolvedDependency, Resolver,
+        Result,
+    };
+
+    /// A project with a dependency `foo` on the `main` branch of a local origin repository
+    struct Project {
+        dir: TempDir,
+        directories: Directories,
+        origin: Repository,
+    }
+
+    impl Project {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let directories = directories(dir.path(), "project");
+            fs::create_dir_all(&directories.deps_dir).unwrap();
+            fs::create_dir_all(&directories.local_git_workdirs).unwrap();
+            let origin = Repository::init_opts(
+                dir.path().join("origin"),
+                RepositoryInitOptions::new().initial_head("main"),
+            )
+            .unwrap();
+            origin
+                .config()
+                .unwrap()
+                .set_bool("uploadpack.allowFilter", true)
+                .unwrap();
+
+            Project {
+                dir,
+                directories,
+                origin,
+            }
+        }
+
+        fn path(&self, path: &str) -> PathBuf {
+            self.dir.path().join(path)
+        }
+
+        fn url(&self) -> String {
+            format!("file://{}", self.path("origin").display())
+        }
+
+        /// Commits `files` to `main` of the origin repository
+        fn commit(&self, files: &[(&str, &str)]) -> Oid {
+            let workdir = self.origin.workdir().unwrap();
+            let mut index = self.origin.index().unwrap();
+            for (path, content) in files {
+                let file = workdir.join(path);
+                fs::create_dir_all(file.parent().unwrap()).unwrap();
+                fs::write(file, content).unwrap();
+                index.add_path(Path::new(path)).unwrap();
+            }
+            index.write().unwrap();
+            let tree = self.origin.find_tree(index.write_tree().unwrap()).unwrap();
+            let signature = Signature::now("test", "test@localhost").unwrap();
+            let parent = self.origin.head().and_then(|h| h.peel_to_commit()).ok();
+            self.origin
+                .commit(
+                    Some("HEAD"),
+                    &signature,
+                    &signature,
+                    "commit",
+                    &tree,
+                    &parent.iter().collect::<Vec<_>>(),
+                )
+                .unwrap()
+        }
+
+        fn dependency(&self) -> Dependency {
+            git_dependency(
+                &self.url(),
+                GitRef::Branch {
+                    branch: "main".to_string(),
+                },
+            )
+        }
+
+        fn worktree(&self) -> PathBuf {
+            self.directories.local_git_workdirs.join("foo")
+        }
+
+        fn acquire(
+            &self,
+            dependency: &Dependency,
+            on_dirty: DirtyWorktree,
+        ) -> Result<Option<Pinned>> {
+            let options = AcquireOptions {
+                on_dirty,
+                pedantic: false,
+                offline: false,
+            };
+            self.acquire_in(&self.directories, dependency, &options)
+        }
+
+        /// Directories of another project sharing the global bare repositories
+        fn other_project(&self) -> Directories {
+            let directories = directories(self.dir.path(), "other");
+            fs::create_dir_all(&directories.deps_dir).unwrap();
+            fs::create_dir_all(&directories.local_git_workdirs).unwrap();
+
+            directories
+        }
+
+        fn acquire_in(
+            &self,
+            directories: &Directories,
+            dependency: &Dependency,
+            options: &AcquireOptions,
+        ) -> Result<Option<Pinned>> {
+            let resolver = Resolver::new(Config {
+                dependencies: vec![("foo".to_string(), dependency.clone())]
+                    .into_iter()
+                    .collect(),
+            });
+            let target = directories.deps_dir.join("foo");
+            let worktree = directories.local_git_workdirs.join("foo");
+            resolver.resolve("foo")?.acquire(
+                DependencyDirs {
+                    base: directories,
+                    std_target_dir: &target,
+                    in_tree_target_dirs: dependency.links.iter().map(PathBuf::as_path).collect(),
+                    local_git_worktree: &worktree,
+                },
+                options,
+                &mut |_| {},
+            )
+        }
+    }
+
+    fn config(git_ref: GitRef) -> Config {
+        Config {
+            dependencies: vec![(
+                "foo".to_string(),
+                git_dependency("https://example.com/foo", git_ref),
+            )]
+            .into_iter()
+            .collect(),
+        }
+    }
+
+    fn lock_file(git_ref: GitRef) -> LockFile {
+        LockFile {
+            dependencies: vec![(
+                "foo".to_string(),
+                LockedDependency::GitRepository {
+                    git_repo: "https://example.com/foo".to_string(),
+                    git_ref,
+                    commit: "ed3467511a8128786c3b23249efc8c56c414e31c".to_string(),
+                    tag: None,
+                },
+            )]
+            .into_iter()
+            .collect(),
+        }
+    }
+
+    fn checkout_ref(resolver: &Resolver) -> String {
+        match &resolver.resolve_all().unwrap()["foo"] {
+            ResolvedDependency::GitRepository { checkout_ref, .. } => checkout_ref.clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn dirty_worktrees() {
+        let project = Project::new();
+        project.commit(&[("a.txt", "1")]);
+        let dependency = project.dependency();
+        project.acquire(&dependency, DirtyWorktree::Abort).unwrap();
+        let file = project.worktree().join("a.txt");
+
+        fs::write(&file, "local").unwrap();
+        project.commit(&[("a.txt", "2")]);
+        let err = project
+            .acquire(&dependency, DirtyWorktree::Abort)
+            .unwrap_err();
+        assert!(err.to_string().contains("a.txt"), "{}", err);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "local");
+
+        project.acquire(&dependency, DirtyWorktree::Stash).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "2");
+        let mut worktree = Repository::open(project.worktree()).unwrap();
+        let mut stashes = 0;
+        worktree
+            .stash_foreach(|_, _, _| {
+                stashes += 1;
+                true
+            })
+            .unwrap();
+        assert_eq!(stashes, 1);
+
+        fs::write(&file, "local").unwrap();
+        project.commit(&[("a.txt", "3")]);
+        project.acquire(&dependency, DirtyWorktree::Force).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "3");
+    }
+
+    #[test]
+    fn replaced_worktree_keeps_changes() {
+        let project = Project::new();
+        project.commit(&[("a.txt", "1")]);
+        project
+            .acquire(&project.dependency(), DirtyWorktree::Abort)
+            .unwrap();
+        let untracked = project.worktree().join("new.txt");
+        fs::write(&untracked, "local").unwrap();
+
+        // a fork has its own global bare repository
+        let fork = project.path("fork");
+        Repository::clone(&project.url(), &fork).unwrap();
+        let mut dependency = project.dependency();
+        dependency.source = DependencySource::GitRepository {
+            git_repo: format!("file://{}", fork.display()),
+            git_ref: GitRef::Branch {
+                branch: "main".to_string(),
+            },
+        };
+        let err = project
+            .acquire(&dependency, DirtyWorktree::Abort)
+            .unwrap_err();
+        assert!(err.to_string().contains("new.txt"), "{}", err);
+        assert!(untracked.exists());
+
+        project.acquire(&dependency, DirtyWorktree::Force).unwrap();
+        assert!(!untracked.exists());
+        assert!(project.worktree().join("a.txt").exists());
+    }
+
+    #[test]
+    fn removed_worktree_keeps_untracked_files() {
+        let project = Project::new();
+        project.commit(&[("a.txt", "1")]);
+        project
+            .acquire(&project.dependency(), DirtyWorktree::Abort)
+            .unwrap();
+        let untracked = project.worktree().join("new.txt");
+        fs::write(&untracked, "local").unwrap();
+
+        remove_worktree(&project.worktree(), false, &mut |_| {}).unwrap();
+        assert!(untracked.exists());
+
+        remove_worktree(&project.worktree(), true, &mut |_| {}).unwrap();
+        assert!(!project.worktree().exists());
+    }
+
+    #[test]
+    fn partial_clones_fetch_checked_out_blobs() {
+        let project = Project::new();
+        let first = project.commit(&[("sdk/a.h", "1"), ("docs/a.md", "1")]);
+        project.commit(&[("sdk/a.h", "2"), ("docs/a.md", "2")]);
+        let mut dependency = project.dependency();
+        dependency.source = DependencySource::GitRepository {
+            git_repo: project.url(),
+            git_ref: GitRef::CommitOnly {
+                commit: first.to_string(),
+            },
+        };
+        dependency.filter = Some("blob:none".to_string());
+        dependency.paths = vec!["sdk".to_string()];
+        project.acquire(&dependency, DirtyWorktree::Abort).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(project.worktree().join("sdk/a.h")).unwrap(),
+            "1"
+        );
+        let global_dir = project.directories.global_git_dir(&project.url()).unwrap();
+        let global_repo = Repository::open_bare(global_dir).unwrap();
+        let has_blob = |content: &str| {
+            let blob = Oid::hash_object(ObjectType::Blob, content.as_bytes()).unwrap();
+            global_repo.odb().unwrap().exists(blob)
+        };
+        assert!(has_blob("1"));
+        // neither HEAD of the global repo nor paths outside of the sparse checkout
+        assert!(!has_blob("2"));
+    }
+
+    #[test]
+    fn sparse_paths() {
+        let project = Project::new();
+        project.commit(&[("root.txt", "1"), ("a/a.txt", "1"), ("b/b.txt", "1")]);
+        let mut dependency = project.dependency();
+        dependency.paths = vec!["a".to_string()];
+        project.acquire(&dependency, DirtyWorktree::Abort).unwrap();
+        let exists = |path| project.worktree().join(path).exists();
+        assert!(exists("root.txt") && exists("a/a.txt"));
+        assert!(!exists("b"));
+
+        dependency.paths = vec!["b".to_string()];
+        project.acquire(&dependency, DirtyWorktree::Abort).unwrap();
+        assert!(!exists("a") && exists("b/b.txt"));
+
+        // files left out are no uncommitted changes
+        project.commit(&[("b/b.txt", "2")]);
+        project.acquire(&dependency, DirtyWorktree::Abort).unwrap();
+        assert_eq!(
+            fs::read_to_string(project.worktree().join("b/b.txt")).unwrap(),
+            "2"
+        );
+
+        dependency.paths = vec![];
+        project.acquire(&dependency, DirtyWorktree::Abort).unwrap();
+        assert!(exists("a/a.txt") && exists("b/b.txt"));
+        let worktree = Repository::open(project.worktree()).unwrap();
+        assert!(!worktree.path().join("info/sparse-checkout").exists());
+    }
+
+    #[test]
+    fn clone_working_copies() {
+        let project = Project::new();
+        project.commit(&[("a.txt", "1")]);
+        let target = project.path("clone");
+        let mut dependency = project.dependency();
+        dependency.source = DependencySource::GitRepository {
+            git_repo: project.url(),
+            git_ref: GitRef::Version {
+                version: "^9".to_string(),
+            },
+        };
+        let err = dependency
+            .clone_into(&project.directories, &target, false, &mut |_| {})
+            .unwrap_err();
+        assert_eq!(err.to_string(), "no tag matches version ^9");
+        assert!(!target.exists());
+
+        // an empty target is fine
+        fs::create_dir(&target).unwrap();
+        project
+            .dependency()
+            .clone_into(&project.directories, &target, false, &mut |_| {})
+            .unwrap();
+        assert_eq!(fs::read_to_string(target.join("a.txt")).unwrap(), "1");
+        let repo = Repository::open(&target).unwrap();
+        assert!(!repo.is_worktree());
+        assert_eq!(repo.head().unwrap().shorthand(), Some("main"));
+        let origin = repo.find_remote("origin").unwrap();
+        assert_eq!(origin.url(), Some(project.url().as_str()));
+
+        let err = project
+            .dependency()
+            .clone_into(&project.directories, &target, false, &mut |_| {})
+            .unwrap_err();
+        assert!(err.to_string().contains("is not empty"), "{}", err);
+
+        let dependency = project.dependency();
+        assert!(dependency.check_clone(&target).is_ok());
+        let err = dependency
+            .check_clone(&project.path("missing"))
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("is not a git repository"),
+            "{}",
+            err
+        );
+        let empty = Repository::init(project.path("empty")).unwrap();
+        empty.remote("origin", &project.url()).unwrap();
+        let err = dependency.check_clone(&project.path("empty")).unwrap_err();
+        assert!(err.to_string().contains("has no commits"), "{}", err);
+        repo.remote_set_url("origin", "https://example.com/other")
+            .unwrap();
+        let err = dependency.check_clone(&target).unwrap_err();
+        assert!(err.to_string().contains("does not match"), "{}", err);
+    }
+
+    #[test]
+    fn links() {
+        let project = Project::new();
+        project.commit(&[("a.txt", "1")]);
+        let mut dependency = project.dependency();
+        let link = project.path("project/third_party/foo");
+        dependency.links = vec![link.clone()];
+        project.acquire(&dependency, DirtyWorktree::Abort).unwrap();
+        assert_eq!(fs::read_to_string(link.join("a.txt")).unwrap(), "1");
+
+        // a directory in the way fails before anything is checked out
+        let project = Project::new();
+        project.commit(&[("a.txt", "1")]);
+        let dir = project.path("project/tools");
+        fs::create_dir_all(&dir).unwrap();
+        dependency.links = vec![dir.clone()];
+        let err = project
+            .acquire(&dependency, DirtyWorktree::Abort)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("could not create link {}", dir.display())
+        );
+        assert!(!project.worktree().exists());
+    }
+
+    #[test]
+    fn shallow_clones() {
+        let project = Project::new();
+        project.commit(&[("a.txt", "1")]);
+        project.commit(&[("a.txt", "2")]);
+        let mut dependency = project.dependency();
+        dependency.depth = Some(1);
+        project.acquire(&dependency, DirtyWorktree::Abort).unwrap();
+
+        let global_dir = project.directories.global_git_dir(&project.url()).unwrap();
+        assert!(Repository::open_bare(&global_dir).unwrap().is_shallow());
+        assert_eq!(
+            fs::read_to_string(project.worktree().join("a.txt")).unwrap(),
+            "2"
+        );
+
+        // libgit2 can't copy the objects of a shallow repo
+        let target = project.path("clone");
+        let err = dependency
+            .clone_into(&project.directories, &target, true, &mut |_| {})
+            .unwrap_err();
+        assert!(err.to_string().contains("offline"), "{}", err);
+        assert!(!target.exists());
+
+        // a dependency without depth needs the full history
+        dependency.depth = None;
+        project.acquire(&dependency, DirtyWorktree::Abort).unwrap();
+        assert!(!Repository::open_bare(&global_dir).unwrap().is_shallow());
+    }
+
+    #[test]
+    fn offline() {
+        let project = Project::new();
+        let first = project.commit(&[("a.txt", "1")]);
+        let dependency = project.dependency();
+        project.acquire(&dependency, DirtyWorktree::Abort).unwrap();
+        project.commit(&[("a.txt", "2")]);
+
+        let offline = AcquireOptions {
+            on_dirty: DirtyWorktree::Abort,
+            pedantic: false,
+            offline: true,
+        };
+        project
+            .acquire_in(&project.directories, &dependency, &offline)
+            .unwrap();
+        let worktree = Repository::open(project.worktree()).unwrap();
+        assert_eq!(worktree.head().unwrap().target(), Some(first));
+        assert_eq!(
+            fs::read_to_string(project.worktree().join("a.txt")).unwrap(),
+            "1"
+        );
+
+        let mut uncached = project.dependency();
+        uncached.source = DependencySource::GitRepository {
+            git_repo: project.url(),
+            git_ref: GitRef::Branch {
+                branch: "dev".to_string(),
+            },
+        };
+        let err = project
+            .acquire_in(&project.directories, &uncached, &offline)
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("is not cached"), "{:#}", err);
+        let fork = format!("file://{}", project.path("fork").display());
+        uncached.source = DependencySource::GitRepository {
+            git_repo: fork,
+            git_ref: GitRef::Branch {
+                branch: "main".to_string(),
+            },
+        };
+        let err = project
+            .acquire_in(&project.directories, &uncached, &offline)
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("is not cached"), "{:#}", err);
+    }
+
+    #[test]
+    fn projects_share_global_repos() {
+        let project = Project::new();
+        project.commit(&[("a.txt", "1")]);
+        let dependency = project.dependency();
+        project.acquire(&dependency, DirtyWorktree::Abort).unwrap();
+        let other = project.other_project();
+        let options = AcquireOptions {
+            on_dirty: DirtyWorktree::Abort,
+            pedantic: false,
+            offline: false,
+        };
+        project.acquire_in(&other, &dependency, &options).unwrap();
+
+        assert_eq!(fs::read_dir(&other.global_git_repos).unwrap().count(), 1);
+        let global_dir = project.directories.global_git_dir(&project.url()).unwrap();
+        let global_repo = Repository::open_bare(global_dir).unwrap();
+        assert_eq!(global_repo.worktrees().unwrap().len(), 2);
+        let other_worktree = other.local_git_workdirs.join("foo");
+        assert!(other_worktree.join("a.txt").exists());
+
+        // removing one worktree leaves the other intact
+        remove_worktree(&project.worktree(), false, &mut |_| {}).unwrap();
+        let other_repo = Repository::open(&other_worktree).unwrap();
+        assert!(other_repo.head().is_ok());
+        assert!(other_worktree.join("a.txt").exists());
+    }
+
+    #[test]
+    fn same_downloads_share_cache_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let directories = directories(dir.path(), "project");
+        let archive = |strip_prefix: &str| ResolvedDependency::Archive {
+            url: "https://example.com/foo.tar.gz".to_string(),
+            sha256: "AB".repeat(32),
+            strip_prefix: Some(strip_prefix.into()),
+        };
+        let keys = archive("foo").cache_keys(&directories).unwrap();
+        assert_eq!(
+            keys,
+            archive("foo/bindings").cache_keys(&directories).unwrap()
+        );
+        assert!(keys.contains(&format!("sha256:{}", "ab".repeat(32))));
+
+        let git = |url: &str| ResolvedDependency::GitRepository {
+            url: url.to_string(),
+            fetch_ref: "+refs/heads/main:refs/remotes/origin/main".to_string(),
+            checkout_ref: "refs/remotes/origin/main".to_string(),
+            version: None,
+            containing_ref: None,
+            depth: None,
+            filter: None,
+            paths: vec![],
+            subdir: None,
+        };
+        assert_eq!(
+            git("https://example.com/foo")
+                .cache_keys(&directories)
+                .unwrap(),
+            git("https://example.com/foo.git")
+                .cache_keys(&directories)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn highest_matching_tag() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let signature = Signature::now("test", "test@localhost").unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let commit = repo
+            .commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+            .unwrap();
+        let commit = repo.find_object(commit, None).unwrap();
+        for tag in ["v1.2.0", "1.3.1", "v1.4.0-beta.1", "v2.0.0", "latest"] {
+            repo.tag_lightweight(tag, &commit, false).unwrap();
+        }
+
+        let highest = |version| highest_tag(&repo, "refs/tags/*", version);
+        assert_eq!(highest("^1.2").unwrap(), "refs/tags/1.3.1");
+        assert_eq!(highest("~1.2").unwrap(), "refs/tags/v1.2.0");
+        assert_eq!(highest(">=1.4.0-beta").unwrap(), "refs/tags/v2.0.0");
+        assert!(highest("^3").is_err());
+        assert!(highest("not a version").is_err());
+    }
+
+    #[test]
+    fn contained_commits() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let signature = Signature::now("test", "test@localhost").unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let commit = |message, parents: &[&git2::Commit]| {
+            let id = repo
+                .commit(None, &signature, &signature, message, &tree, parents)
+                .unwrap();
+            repo.find_commit(id).unwrap()
+        };
+        let first = commit("first", &[]);
+        let second = commit("second", &[&first]);
+        let side = commit("side", &[&first]);
+        repo.reference("refs/remotes/origin/main", second.id(), true, "test")
+            .unwrap();
+
+        let main = "refs/remotes/origin/main";
+        assert!(verify_contained(&repo, first.id(), main).is_ok());
+        assert!(verify_contained(&repo, second.id(), main).is_ok());
+        let err = verify_contained(&repo, side.id(), main).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("commit {} is not on main (at {})", side.id(), second.id())
+        );
+    }
+
+    #[test]
+    fn frozen_uses_locked_commit() {
+        let main = GitRef::Branch {
+            branch: "main".to_string(),
+        };
+        let dev = GitRef::Branch {
+            branch: "dev".to_string(),
+        };
+
+        let resolver = Resolver::new(config(main.clone())).with_lock_file(lock_file(main.clone()));
+        assert_eq!(checkout_ref(&resolver), "refs/remotes/origin/main");
+        assert_eq!(
+            checkout_ref(&resolver.frozen(true)),
+            "ed3467511a8128786c3b23249efc8c56c414e31c"
+        );
+
+        // lock entry is outdated if the config changed
+        let resolver = Resolver::new(config(dev))
+            .with_lock_file(lock_file(main))
+            .frozen(true);
+        assert_eq!(checkout_ref(&resolver), "refs/remotes/origin/dev");
+        assert!(resolver.lock_file(&HashMap::new()).dependencies.is_empty());
+    }
+
+    #[test]
+    fn nested_configs() {
+        let main = GitRef::Branch {
+            branch: "main".to_string(),
+        };
+        let dev = GitRef::Branch {
+            branch: "dev".to_string(),
+        };
+        let nested = |name: &str, url: &str, git_ref: GitRef| Config {
+            dependencies: vec![(
+                name.to_string(),
+                Dependency {
+                    target: Some("elsewhere".into()),
+                    ..git_dependency(url, git_ref)
+                },
+            )]
+            .into_iter()
+            .collect(),
+        };
+
+        let mut resolver = Resolver::new(config(main.clone()));
+        // same repository under a different name and url spelling
+        let added = resolver
+            .add_nested_config(
+                "foo",
+                nested("foo2", "https://example.com/foo.git", main.clone()),
+            )
+            .unwrap();
+        assert!(added.is_empty());
+        let added = resolver
+            .add_nested_config(
+                "foo",
+                nested("bar", "https://example.com/bar", main.clone()),
+            )
+            .unwrap();
+        assert_eq!(added, vec!["bar".to_string()]);
+        assert_eq!(resolver.dependency("bar").unwrap().target, None);
+        assert_eq!(resolver.resolve_all().unwrap().len(), 2);
+
+        let err = resolver
+            .add_nested_config("bar", nested("foo", "https://example.com/foo", dev))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("foo -> bar -> foo"), "{}", err);
+        assert!(resolver
+            .add_nested_config("bar", nested("bar", "https://example.com/baz", main))
+            .is_err());
+    }
+
+    #[test]
+    fn paths_inside_root() {
+        let root = Path::new("/project");
+        assert!(is_inside(root, Path::new("third_party/foo")));
+        assert!(is_inside(root, Path::new("./a/../foo")));
+        assert!(is_inside(root, Path::new("/project/foo")));
+        assert!(!is_inside(root, Path::new("../foo")));
+        assert!(!is_inside(root, Path::new("a/../../foo")));
+        assert!(!is_inside(root, Path::new("/elsewhere/foo")));
+    }
+
+    #[test]
+    fn subdir_paths() {
+        let dir = Path::new("/project/.pkgstrap/git/foo");
+        assert_eq!(subdir_path(dir, None).unwrap(), dir);
+        assert_eq!(
+            subdir_path(dir, Some(Path::new("bindings/c"))).unwrap(),
+            dir.join("bindings/c")
+        );
+        assert!(subdir_path(dir, Some(Path::new("../bar"))).is_err());
+        assert!(subdir_path(dir, Some(Path::new("/bindings/c"))).is_err());
+    }
+
+    #[test]
+    fn same_repo_urls() {
+        assert!(same_repo_url(
+            "https://github.com/torkleyy/pkgstrap.git",
+            "https://github.com/torkleyy/pkgstrap"
+        ));
+        assert!(!same_repo_url(
+            "https://github.com/torkleyy/pkgstrap",
+            "https://github.com/torkleyy/sortnbackup"
+        ));
+    }
 
     #[test]
     fn normalize_urls() {
@@ -374,5 +2409,9 @@ mod tests {
                 .replace("\\", "/"),
             "github.com/torkleyy/async-rust-parser"
         );
+        assert_eq!(
+            normalize_url_for_dir("file:///srv/git/foo.git").unwrap(),
+            Path::new("localhost/srv/git/foo")
+        );
     }
 }
@@ -0,0 +1,199 @@
+use std::{
+    fs, iter,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::{Config, Dependency, Directories, Resolver, Result};
+
+/// Symlinks and worktree of a dependency of the previous run that the config doesn't use anymore
+#[derive(Clone, Debug, PartialEq)]
+pub struct StaleDependency {
+    pub name: String,
+    /// The previous target, unless another dependency is linked there now
+    pub target: Option<PathBuf>,
+    /// Previous links that no dependency uses anymore
+    pub links: Vec<PathBuf>,
+    /// Whether the dependency was removed, which makes its worktree stale as well
+    pub removed: bool,
+}
+
+impl Directories {
+    /// Copy of the config of the previous run, which is compared to the current config
+    pub fn previous_config(&self) -> PathBuf {
+        self.pkgstrap_dir.join("pkgstrap.ron.last")
+    }
+
+    /// Records `config_contents` as the config of the previous run.
+    ///
+    /// With `keep_stale`, an existing copy is kept, so that the stale dependencies that were
+    /// kept are still detected by later runs.
+    pub fn record_config(&self, config_contents: &str, keep_stale: bool) -> Result<()> {
+        let previous_config = self.previous_config();
+        if keep_stale && previous_config.exists() {
+            return Ok(());
+        }
+
+        fs::write(previous_config, config_contents).context("could not backup config")
+    }
+}
+
+impl Resolver {
+    /// Dependencies of the `previous` config whose symlinks or worktree aren't used by any
+    /// dependency anymore, sorted by name. Targets default to `<deps_dir>/<name>`.
+    pub fn stale_dependencies(&self, previous: &Config, deps_dir: &Path) -> Vec<StaleDependency> {
+        let target = |name: &str, dependency: &Dependency| {
+            dependency
+                .target
+                .clone()
+                .unwrap_or_else(|| deps_dir.join(name))
+        };
+        let current_targets: Vec<_> = self
+            .dependencies()
+            .flat_map(|(name, dependency)| {
+                iter::once(target(name, dependency)).chain(dependency.links.iter().cloned())
+            })
+            .collect();
+
+        let mut names: Vec<_> = previous.dependencies.keys().collect();
+        names.sort();
+        names
+            .into_iter()
+            .filter_map(|name| {
+                let previous_dependency = &previous.dependencies[name];
+                // the symlinks may have been taken over by a renamed or moved dependency
+                let target = Some(target(name, previous_dependency))
+                    .filter(|target| !current_targets.contains(target));
+                let links: Vec<_> = previous_dependency
+                    .links
+                    .iter()
+                    .filter(|link| !current_targets.contains(link))
+                    .cloned()
+                    .collect();
+                let removed = self.dependency(name).is_none();
+                if !removed && target.is_none() && links.is_empty() {
+                    return None;
+                }
+
+                Some(StaleDependency {
+                    name: name.clone(),
+                    target,
+                    links,
+                    removed,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use crate::{
+        stale::StaleDependency,
+        test_util::{directories, git_dependency},
+        Config, Dependency, GitRef, Resolver,
+    };
+
+    fn config(dependencies: &[(&str, Option<&str>, &[&str])]) -> Config {
+        let main = GitRef::Branch {
+            branch: "main".to_string(),
+        };
+        Config {
+            dependencies: dependencies
+                .iter()
+                .map(|(name, target, links)| {
+                    let dependency = Dependency {
+                        target: target.map(PathBuf::from),
+                        links: links.iter().map(PathBuf::from).collect(),
+                        ..git_dependency(&format!("https://example.com/{}", name), main.clone())
+                    };
+                    (name.to_string(), dependency)
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn stale_dependencies() {
+        let deps = PathBuf::from("deps");
+        let previous = config(&[
+            ("bar", None, &["tools/bar"]),
+            ("baz", None, &[]),
+            ("foo", Some("third_party/foo"), &["tools/foo", "tools/foo2"]),
+        ]);
+        let stale = |current: &[(&str, Option<&str>, &[&str])]| {
+            Resolver::new(config(current)).stale_dependencies(&previous, &deps)
+        };
+
+        assert_eq!(
+            stale(&[
+                ("bar", None, &["tools/bar"]),
+                ("baz", None, &[]),
+                ("foo", Some("third_party/foo"), &["tools/foo", "tools/foo2"]),
+            ]),
+            vec![]
+        );
+
+        // removed, with a dropped link, and renamed while keeping its target and a link
+        assert_eq!(
+            stale(&[
+                ("bar", None, &[]),
+                ("qux", Some("third_party/foo"), &["tools/foo"]),
+            ]),
+            vec![
+                StaleDependency {
+                    name: "bar".to_string(),
+                    target: None,
+                    links: vec!["tools/bar".into()],
+                    removed: false,
+                },
+                StaleDependency {
+                    name: "baz".to_string(),
+                    target: Some(deps.join("baz")),
+                    links: vec![],
+                    removed: true,
+                },
+                StaleDependency {
+                    name: "foo".to_string(),
+                    target: None,
+                    links: vec!["tools/foo2".into()],
+                    removed: true,
+                },
+            ]
+        );
+
+        // moved to another target, while another dependency took over a link
+        assert_eq!(
+            stale(&[
+                ("bar", None, &["tools/bar", "tools/foo2"]),
+                ("baz", None, &[]),
+                ("foo", None, &["tools/foo"]),
+            ]),
+            vec![StaleDependency {
+                name: "foo".to_string(),
+                target: Some("third_party/foo".into()),
+                links: vec![],
+                removed: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn recorded_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let directories = directories(dir.path(), "project");
+        fs::create_dir_all(&directories.pkgstrap_dir).unwrap();
+        let recorded = || fs::read_to_string(directories.previous_config()).unwrap();
+
+        directories.record_config("first", true).unwrap();
+        assert_eq!(recorded(), "first");
+        // the dependencies kept stale are only in the first config
+        directories.record_config("second", true).unwrap();
+        assert_eq!(recorded(), "first");
+        directories.record_config("third", false).unwrap();
+        assert_eq!(recorded(), "third");
+    }
+}
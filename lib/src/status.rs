@@ -0,0 +1,281 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use git2::Repository;
+use serde::Serialize;
+
+use crate::{
+    resolved::{dirty_files, highest_tag, subdir_path},
+    DependencyDirs, LockedDependency, ResolvedDependency, Resolver, Result,
+};
+
+/// State of a dependency on disk, as reported by [`Resolver::status`]
+#[derive(Clone, Debug, Serialize)]
+pub struct DependencyStatus {
+    /// Human readable description of where the dependency comes from
+    pub source: String,
+    /// Checked out commit, or checksum of the linked artifact
+    pub version: Option<String>,
+    /// Whether `version` is the one in the lock file, if the dependency is locked
+    pub matches_lock: Option<bool>,
+    /// Whether `version` is the one the config (or override) asks for, if known without fetching
+    pub matches_ref: Option<bool>,
+    /// Commits ahead of and behind the ref from the config, as last fetched
+    pub ahead_behind: Option<(usize, usize)>,
+    pub dirty_files: Vec<String>,
+    pub link: LinkStatus,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkStatus {
+    Valid,
+    Missing,
+    /// The symlink points to a path that doesn't exist
+    Broken(PathBuf),
+    /// The symlink points somewhere else than expected
+    WrongTarget(PathBuf),
+    NotSymlink,
+    /// The path the symlink should point to doesn't exist
+    MissingTarget(PathBuf),
+}
+
+impl Resolver {
+    /// Inspects dependency `name` without fetching or modifying anything
+    pub fn status(&self, name: &str, dirs: DependencyDirs) -> Result<DependencyStatus> {
+        let dependency = self
+            .dependency(name)
+            .ok_or_else(|| anyhow!("unknown dependency {}", name))?;
+        // a missing override path is reported as its link status
+        let resolved = self.resolve_unchecked(name)?;
+        let locked = self.locked(name, &dependency.source);
+        let overridden = self.is_overridden(name);
+
+        let mut status = DependencyStatus {
+            source: describe(&resolved, overridden),
+            version: None,
+            matches_lock: None,
+            matches_ref: None,
+            ahead_behind: None,
+            dirty_files: vec![],
+            link: link_status(dirs.std_target_dir, None),
+        };
+
+        match &resolved {
+            ResolvedDependency::GitRepository {
+                checkout_ref,
+                version,
+                subdir,
+                ..
+            } => {
+                let worktree = dirs.local_git_worktree;
+                let linked_dir = subdir_path(worktree, subdir.as_deref())?;
+                status.link = link_status(dirs.std_target_dir, Some(&linked_dir));
+                let repo = match Repository::open(worktree) {
+                    Ok(repo) => repo,
+                    Err(_) => return Ok(status),
+                };
+                let head = repo
+                    .head()
+                    .and_then(|h| h.peel_to_commit())
+                    .context("could not resolve HEAD")?
+                    .id();
+
+                status.version = Some(head.to_string());
+                status.dirty_files = dirty_files(&repo, false)?;
+                status.matches_lock = match locked {
+                    Some(LockedDependency::GitRepository { commit, .. }) if !overridden => {
+                        Some(commit == &head.to_string())
+                    }
+                    _ => None,
+                };
+                let checkout_ref = match version {
+                    Some(version) => match highest_tag(&repo, checkout_ref, version) {
+                        Ok(tag) => tag,
+                        Err(_) => return Ok(status),
+                    },
+                    None => checkout_ref.clone(),
+                };
+                let expected = repo
+                    .revparse_single(&checkout_ref)
+                    .and_then(|o| o.peel_to_commit())
+                    .map(|c| c.id());
+                if let Ok(expected) = expected {
+                    status.matches_ref = Some(expected == head);
+                    status.ahead_behind = repo.graph_ahead_behind(head, expected).ok();
+                }
+            }
+            ResolvedDependency::LocalPath { local_path, .. } if !local_path.exists() => {
+                status.link = LinkStatus::MissingTarget(local_path.clone());
+            }
+            ResolvedDependency::LocalPath { local_path, subdir } => {
+                let linked_dir = subdir_path(local_path, subdir.as_deref())?;
+                status.link = link_status(dirs.std_target_dir, Some(&linked_dir));
+                if let Ok(repo) = Repository::open(local_path) {
+                    status.version = repo
+                        .head()
+                        .and_then(|h| h.peel_to_commit())
+                        .map(|c| c.id().to_string())
+                        .ok();
+                    status.dirty_files = dirty_files(&repo, false)?;
+                }
+            }
+            ResolvedDependency::Archive { sha256, .. } => {
+                status.version = linked_artifact(&dirs);
+                status.matches_ref = status
+                    .version
+                    .as_ref()
+                    .map(|v| v.eq_ignore_ascii_case(sha256));
+            }
+            ResolvedDependency::GithubRelease { sha256, .. } => {
+                status.version = linked_artifact(&dirs);
+                status.matches_ref = sha256
+                    .as_ref()
+                    .zip(status.version.as_ref())
+                    .map(|(expected, v)| v.eq_ignore_ascii_case(expected));
+                status.matches_lock = match locked {
+                    Some(LockedDependency::GithubRelease { sha256, .. }) if !overridden => {
+                        status.version.as_ref().map(|v| v == sha256)
+                    }
+                    _ => None,
+                };
+            }
+            ResolvedDependency::AzureArtifact { .. } => {
+                status.version = linked_artifact(&dirs);
+                status.matches_lock = match locked {
+                    Some(LockedDependency::AzureArtifact { sha256, .. }) if !overridden => {
+                        status.version.as_ref().map(|v| v == sha256)
+                    }
+                    _ => None,
+                };
+            }
+        }
+
+        Ok(status)
+    }
+}
+
+fn describe(resolved: &ResolvedDependency, overridden: bool) -> String {
+    let description = match resolved {
+        ResolvedDependency::GitRepository {
+            url,
+            version: Some(version),
+            ..
+        } => format!("git {} at version {}", url, version),
+        ResolvedDependency::GitRepository {
+            url, checkout_ref, ..
+        } => format!("git {} at {}", url, checkout_ref),
+        ResolvedDependency::Archive { url, .. } => format!("archive {}", url),
+        ResolvedDependency::GithubRelease {
+            repo, tag, asset, ..
+        } => format!("release {} of {}, asset {}", tag, repo, asset),
+        ResolvedDependency::AzureArtifact {
+            organization,
+            feed,
+            package,
+            version,
+            ..
+        } => format!(
+            "package {} {} from {}/{}",
+            package, version, organization, feed
+        ),
+        ResolvedDependency::LocalPath { local_path, .. } => {
+            format!("local path {}", local_path.display())
+        }
+    };
+
+    if overridden {
+        format!("{} (override)", description)
+    } else {
+        description
+    }
+}
+
+fn link_status(link: &Path, expected: Option<&Path>) -> LinkStatus {
+    match fs::symlink_metadata(link) {
+        Err(_) => LinkStatus::Missing,
+        Ok(m) if !m.file_type().is_symlink() => LinkStatus::NotSymlink,
+        Ok(_) => {
+            let destination = fs::read_link(link).unwrap_or_default();
+            match link.canonicalize() {
+                Err(_) => LinkStatus::Broken(destination),
+                Ok(actual) => match expected.map(Path::canonicalize) {
+                    Some(Ok(expected)) if expected != actual => {
+                        LinkStatus::WrongTarget(destination)
+                    }
+                    _ => LinkStatus::Valid,
+                },
+            }
+        }
+    }
+}
+
+/// Checksum of the cached artifact the dependency symlink points into
+fn linked_artifact(dirs: &DependencyDirs) -> Option<String> {
+    let destination = fs::read_link(dirs.std_target_dir).ok()?;
+    let cache = dirs
+        .base
+        .global_artifacts
+        .join("sha256")
+        .canonicalize()
+        .ok()?;
+    let relative = destination.strip_prefix(cache).ok()?;
+
+    relative
+        .components()
+        .next()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        test_util::{directories, git_dependency},
+        Config, ConfigOverrides, DependencyDirs, DependencyOverride, GitRef, LinkStatus, Resolver,
+    };
+
+    #[test]
+    fn missing_override_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let dependency = git_dependency(
+            "https://example.com/foo",
+            GitRef::Branch {
+                branch: "main".to_string(),
+            },
+        );
+        let missing = dir.path().join("missing");
+        let resolver = Resolver::new(Config {
+            dependencies: vec![("foo".to_string(), dependency)].into_iter().collect(),
+        })
+        .with_config_overrides(ConfigOverrides {
+            dependencies: vec![(
+                "foo".to_string(),
+                DependencyOverride::LocalPath {
+                    local_path: missing.clone(),
+                },
+            )]
+            .into_iter()
+            .collect(),
+        });
+        let directories = directories(dir.path(), "project");
+
+        assert!(resolver.resolve("foo").is_err());
+        let status = resolver
+            .status(
+                "foo",
+                DependencyDirs {
+                    base: &directories,
+                    std_target_dir: &directories.deps_dir.join("foo"),
+                    in_tree_target_dirs: vec![],
+                    local_git_worktree: &PathBuf::from("unused"),
+                },
+            )
+            .unwrap();
+        assert_eq!(status.link, LinkStatus::MissingTarget(missing));
+    }
+}
@@ -0,0 +1,33 @@
+//! Fixtures shared by the tests of several modules
+
+use std::path::Path;
+
+use crate::{Dependency, DependencySource, Directories, GitRef};
+
+/// Directories of the project `<root>/<project>`, sharing the global caches in `root`
+pub(crate) fn directories(root: &Path, project: &str) -> Directories {
+    let pkgstrap_dir = root.join(project).join(".pkgstrap");
+    Directories {
+        deps_dir: pkgstrap_dir.join("deps"),
+        local_git_workdirs: pkgstrap_dir.join("git"),
+        pkgstrap_dir,
+        global_git_repos: root.join("git-repos"),
+        global_artifacts: root.join("artifacts"),
+    }
+}
+
+/// A git dependency without any of the optional settings
+pub(crate) fn git_dependency(url: &str, git_ref: GitRef) -> Dependency {
+    Dependency {
+        source: DependencySource::GitRepository {
+            git_repo: url.to_string(),
+            git_ref,
+        },
+        target: None,
+        depth: None,
+        filter: None,
+        paths: vec![],
+        subdir: None,
+        links: vec![],
+    }
+}
@@ -0,0 +1,73 @@
+use std::{fmt, path::PathBuf};
+
+/// A condition that is tolerated, but rejected in pedantic mode
+#[derive(Clone, Debug, PartialEq)]
+pub enum Warning {
+    MissingLockEntry {
+        dependency: String,
+    },
+    UnpinnedBranch {
+        dependency: String,
+        branch: String,
+    },
+    TargetOutsideRoot {
+        dependency: String,
+        target: PathBuf,
+    },
+    /// A local path override whose remote is not the repository of the dependency
+    MismatchedRemote {
+        dependency: String,
+        local_path: PathBuf,
+        remote: String,
+    },
+    /// The config of the previous run, used to find stale dependencies, is invalid
+    InvalidPreviousConfig {
+        error: String,
+    },
+    StaleNotRemoved {
+        dependency: String,
+        error: String,
+    },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::MissingLockEntry { dependency } => {
+                write!(f, "dependency {} is not in the lock file", dependency)
+            }
+            Warning::UnpinnedBranch { dependency, branch } => write!(
+                f,
+                "dependency {} follows branch {} without a pinned commit",
+                dependency, branch
+            ),
+            Warning::TargetOutsideRoot { dependency, target } => write!(
+                f,
+                "target {} of dependency {} is outside of the project root",
+                target.display(),
+                dependency
+            ),
+            Warning::MismatchedRemote {
+                dependency,
+                local_path,
+                remote,
+            } => write!(
+                f,
+                "{} has remote {}, which does not match the config of dependency {}",
+                local_path.display(),
+                remote,
+                dependency
+            ),
+            Warning::InvalidPreviousConfig { error } => {
+                write!(f, "could not read previous config: {}", error)
+            }
+            Warning::StaleNotRemoved { dependency, error } => {
+                write!(
+                    f,
+                    "could not remove stale dependency {}: {}",
+                    dependency, error
+                )
+            }
+        }
+    }
+}
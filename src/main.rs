@@ -1,13 +1,23 @@
 use std::{
+    collections::{HashMap, HashSet, VecDeque},
     fs,
     fs::{read_to_string, rename},
-    path::PathBuf,
+    io::{self, Write},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::mpsc,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, bail, Context};
+use indicatif::{
+    HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle,
+};
 use pkgstrap_lib::*;
-use remove_dir_all::remove_dir_all;
-use ron_reboot::from_str_serde;
+use ron_reboot::{from_str_serde, to_pretty_string_serde};
+use serde_json::{json, Value};
 use structopt::StructOpt;
 
 /// pkgstrap
@@ -25,8 +35,43 @@ struct Opt {
     /// be downloaded according to the version specified in the lock file.
     /// Dependencies not present in the lock file will be downloaded in the latest
     /// version matching the specification (in pkgstrap.ron).
-    #[structopt(short, long, parse(from_occurrences))]
-    frozen: u8,
+    #[structopt(short, long)]
+    frozen: bool,
+    /// Pedantic mode for continuous integration. Implies `--frozen` and turns warnings
+    /// (e.g. missing lock entries or uncommitted changes in dependencies) into errors.
+    #[structopt(long)]
+    pedantic: bool,
+    /// Skips all fetches, clones and downloads, setting up dependencies from the global cache
+    /// only. Implies `--frozen`.
+    #[structopt(long)]
+    offline: bool,
+    /// Ignores the overrides file, if any
+    #[structopt(long)]
+    no_overrides: bool,
+    /// Stashes uncommitted changes in dependency worktrees before updating them
+    #[structopt(long, conflicts_with = "force")]
+    stash: bool,
+    /// Discards uncommitted changes in dependency worktrees when updating them
+    #[structopt(long)]
+    force: bool,
+    /// Keeps symlinks and worktrees of dependencies that were removed from the config
+    #[structopt(long)]
+    keep_stale: bool,
+    /// Clones all git dependencies into `<dir>/<name>` and overrides them to use those clones
+    #[structopt(short = "C", long, value_name = "dir")]
+    clone_all: Option<PathBuf>,
+    /// Output format: `human` or `json` (one event per line)
+    #[structopt(long, default_value = "human", possible_values = &["human", "json"])]
+    message_format: MessageFormat,
+    /// Number of dependencies to fetch and check out concurrently
+    #[structopt(short, long, default_value = "1")]
+    jobs: usize,
+    /// Clones dependency `<name>` into `<dir>` and overrides it to use that clone
+    #[structopt(short, long, number_of_values = 2, value_names = &["name", "dir"])]
+    clone: Vec<String>,
+    /// Overrides dependency `<name>` to use the existing checkout at `<dir>`
+    #[structopt(short, long, number_of_values = 2, value_names = &["name", "dir"])]
+    path: Vec<String>,
     #[structopt(long, default_value = "pkgstrap.ron")]
     config: PathBuf,
     #[structopt(long, default_value = ".pkgstrap")]
@@ -37,9 +82,11 @@ struct Opt {
 
 #[derive(StructOpt, Debug)]
 enum SubCommand {
-    /// Cleans up dependency symlinks & git repos
+    /// Removes dependency symlinks and worktrees, pruning the worktrees from the global git repos.
+    ///
+    /// Worktrees with uncommitted changes are kept unless `--force` is given.
     Clean {
-        /// Whether to clean deps directory.
+        /// Whether to clean deps directory and the `links` of the dependencies.
         #[structopt(long = "no-deps-dir", parse(from_flag = std::ops::Not::not))]
         deps_dir: bool,
         /// Whether to clean git repos.
@@ -48,138 +95,1025 @@ enum SubCommand {
         /// Whether to "clean" overrides as well. Will rename the file to `overrides.ron.bk`.
         #[structopt(long)]
         overrides: bool,
+        /// Removes worktrees with uncommitted changes as well
+        #[structopt(long)]
+        force: bool,
     },
     /// Clone a dependency and setup an override
     Clone { dependency: String, target: PathBuf },
+    /// Shows the state of every dependency without fetching or changing anything
+    Status,
+    /// Manages the global cache of bare git repos
+    Cache {
+        #[structopt(subcommand)]
+        command: CacheCommand,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum CacheCommand {
+    /// Lists cached repos with their size, last use and the worktrees linked to them
+    List,
+    /// Removes repos that weren't used recently and have no worktrees, and runs `git gc` on
+    /// the others
+    Gc {
+        /// Number of days after which an unused repo is removed
+        #[structopt(long, default_value = "30")]
+        days: u64,
+    },
+    /// Removes the cached repo of a url. Fails if worktrees still use it, unless `--force`
+    /// is given.
+    Remove {
+        url: String,
+        /// Removes the cached repo even if worktrees still use it
+        #[structopt(long)]
+        force: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MessageFormat {
+    Human,
+    Json,
+}
+
+impl FromStr for MessageFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "human" => Ok(MessageFormat::Human),
+            "json" => Ok(MessageFormat::Json),
+            _ => bail!("unknown message format {}", s),
+        }
+    }
 }
 
 trait OrPrint {
-    fn or_print(self);
+    fn or_print(self, opt: &Opt);
 }
 
 impl<T> OrPrint for Result<T> {
-    fn or_print(self) {
-        match self {
-            Err(e) => print_err(e),
-            _ => {}
+    fn or_print(self, opt: &Opt) {
+        if let Err(e) = self {
+            print_err(opt, e)
         }
     }
 }
 
-fn print_err(e: Error) {
-    eprintln!("error: {}", e);
-    e.chain()
-        .skip(1)
-        .for_each(|cause| eprintln!("caused by: {}", cause));
+fn print_err(opt: &Opt, e: Error) {
+    let causes: Vec<_> = e.chain().skip(1).map(ToString::to_string).collect();
+    emit(
+        opt,
+        json!({ "event": "error", "message": e.to_string(), "causes": causes }),
+        || {
+            eprintln!("error: {}", e);
+            causes
+                .iter()
+                .for_each(|cause| eprintln!("caused by: {}", cause));
+        },
+    );
+}
+
+/// Prints `event` as a JSON line with `--message-format json`, otherwise runs `print_human`
+fn emit(opt: &Opt, event: Value, print_human: impl FnOnce()) {
+    match opt.message_format {
+        MessageFormat::Json => println!("{}", event),
+        MessageFormat::Human => print_human(),
+    }
 }
 
 fn main() {
-    if let Err(e) = app() {
-        print_err(e);
+    let opt: Opt = Opt::from_args();
+
+    if let Err(e) = app(&opt) {
+        print_err(&opt, e);
         std::process::exit(1);
     }
 }
 
-fn app() -> Result<()> {
-    let matches: Opt = Opt::from_args();
+impl Opt {
+    fn directories(&self) -> Result<Directories> {
+        let global_dir = dirs::home_dir().context("no home dir")?.join(".pkgstrap");
 
-    let directories = Directories {
-        deps_dir: matches.pkgstrap_dir.join("deps"),
-        local_git_workdirs: matches.pkgstrap_dir.join("git"),
-        pkgstrap_dir: matches.pkgstrap_dir,
-        global_git_repos: dirs::home_dir()
-            .context("no home dir")?
-            .join(".pkgstrap")
-            .join("git-repos"),
-    };
+        Ok(Directories {
+            deps_dir: self.pkgstrap_dir.join("deps"),
+            local_git_workdirs: self.pkgstrap_dir.join("git"),
+            pkgstrap_dir: self.pkgstrap_dir.clone(),
+            global_git_repos: global_dir.join("git-repos"),
+            global_artifacts: global_dir.join("artifacts"),
+        })
+    }
+
+    fn override_file(&self) -> PathBuf {
+        self.pkgstrap_dir.join("overrides.ron")
+    }
+
+    fn lock_file(&self) -> PathBuf {
+        self.config.with_file_name("pkgstrap-lock.ron")
+    }
+}
 
+fn read_config(config_file: &Path) -> Result<(Config, String)> {
+    let config_contents = read_to_string(config_file).context("could not open config")?;
+    let config = from_str_serde(&config_contents).context("could not parse config")?;
+
+    Ok((config, config_contents))
+}
+
+fn read_overrides(override_file: &Path) -> Result<ConfigOverrides> {
+    from_str_serde(&read_to_string(override_file).context("could not open overrides")?)
+        .context("could not parse overrides")
+}
+
+fn write_overrides(override_file: &Path, overrides: &ConfigOverrides) -> Result<()> {
+    let overrides = to_pretty_string_serde(overrides, Default::default())
+        .context("could not serialize overrides")?;
+    fs::write(override_file, overrides).context("could not write overrides")
+}
+
+fn app(matches: &Opt) -> Result<()> {
+    let directories = matches.directories()?;
     let Directories {
-        pkgstrap_dir,
         deps_dir,
         local_git_workdirs,
-        global_git_repos,
+        ..
     } = &directories;
-    let override_file = pkgstrap_dir.join("overrides.ron");
-    let override_file = &override_file;
-    let config_file = &matches.config;
+    let override_file = &matches.override_file();
 
-    match matches.subcommand {
+    match &matches.subcommand {
         None => {
-            let config_contents = read_to_string(config_file).context("could not open config")?;
-
-            let config: Config =
-                from_str_serde(&config_contents).context("could not parse config")?;
-
-            let mut resolver = Resolver::new(config.clone());
+            if let Some(clone_dir) = &matches.clone_all {
+                let (config, _) = read_config(&matches.config)?;
 
-            std::fs::create_dir_all(&pkgstrap_dir).unwrap();
-            std::fs::create_dir_all(&deps_dir).unwrap();
-            std::fs::create_dir_all(&global_git_repos).unwrap();
-            std::fs::create_dir_all(&local_git_workdirs).unwrap();
+                let mut names: Vec<_> = config.dependencies.keys().cloned().collect();
+                names.sort();
+                let mut local_paths = vec![];
+                for name in names {
+                    if config.dependencies[&name].source.git_repo_url().is_none() {
+                        emit(
+                            matches,
+                            json!({ "event": "clone_skipped", "dependency": name, "reason": "not a git repository" }),
+                            || {
+                                println!(
+                                    "Dependency {} is not a git repository, skipping clone",
+                                    name
+                                )
+                            },
+                        );
+                        continue;
+                    }
 
-            if override_file.exists() {
-                let overrides: ConfigOverrides = from_str_serde(
-                    &read_to_string(&override_file).context("could not open overrides")?,
-                )
-                .context("could not parse overrides")?;
-                resolver = resolver.with_config_overrides(overrides);
+                    let target = clone_dir.join(&name);
+                    if target.exists() {
+                        config.dependencies[&name]
+                            .check_clone(&target)
+                            .with_context(|| {
+                                anyhow!("cannot use {} for dependency {}", target.display(), name)
+                            })?;
+                        emit(
+                            matches,
+                            json!({ "event": "clone_skipped", "dependency": name, "target": target }),
+                            || {
+                                println!(
+                                    "Dependency {} already exists at {}, skipping clone",
+                                    name,
+                                    target.display()
+                                )
+                            },
+                        );
+                    } else {
+                        clone_dependency(matches, &directories, &config, &name, &target)?;
+                    }
+                    local_paths.push((name, target));
+                }
+                add_local_overrides(matches, local_paths)?;
             }
 
-            let resolved = resolver.resolve_all()?;
+            if !matches.clone.is_empty() {
+                let (config, _) = read_config(&matches.config)?;
 
-            for (name, dep) in resolved.iter() {
-                println!("Setting up dependency {}...", name);
+                let mut local_paths = vec![];
+                for pair in matches.clone.chunks(2) {
+                    let (name, target) = (&pair[0], PathBuf::from(&pair[1]));
+                    clone_dependency(matches, &directories, &config, name, &target)?;
+                    local_paths.push((name.clone(), target));
+                }
+                add_local_overrides(matches, local_paths)?;
+            }
 
-                let target = config.dependencies[name]
-                    .target
-                    .clone()
-                    .unwrap_or_else(|| deps_dir.join(name));
-                dep.acquire(DependencyDirs {
-                    base: &directories,
-                    std_target_dir: &target,
-                    in_tree_target_dirs: vec![],
+            if !matches.path.is_empty() {
+                let (config, _) = read_config(&matches.config)?;
 
-                    local_git_worktree: &local_git_workdirs.join(name),
-                })
-                .with_context(|| anyhow!("failed to acquire dependency {}", name))?;
+                let mut local_paths = vec![];
+                for pair in matches.path.chunks(2) {
+                    let (name, local_path) = (&pair[0], PathBuf::from(&pair[1]));
+                    let dep = find_dependency(&config, name)?;
+                    check_local_path(&local_path)?;
+                    if let Some(remote) = dep.mismatched_remote(&local_path) {
+                        report_warnings(
+                            matches,
+                            &[Warning::MismatchedRemote {
+                                dependency: name.clone(),
+                                local_path: local_path.clone(),
+                                remote,
+                            }],
+                        )?;
+                    }
+                    local_paths.push((name.clone(), local_path));
+                }
+                add_local_overrides(matches, local_paths)?;
             }
 
-            fs::write(pkgstrap_dir.join("pkgstrap.ron.last"), config_contents)
-                .context("could not backup config")?;
+            update(matches, &directories)?
         }
         Some(SubCommand::Clean {
             deps_dir: clean_deps_dir,
             git: clean_git_dir,
             overrides: clean_overrides,
+            force,
         }) => {
-            println!("note: the clean subcommand does not work reliably and may print errors");
-
-            if clean_deps_dir && deps_dir.exists() {
-                remove_dir_all(deps_dir)
-                    .context("could not remove deps dir")
-                    .or_print();
+            if *clean_deps_dir {
+                remove_links(matches, &directories).or_print(matches);
             }
-            if clean_git_dir && local_git_workdirs.exists() {
-                // TODO: remove workdir from parent repo
-                remove_dir_all(local_git_workdirs)
-                    .context("could not remove git dir")
-                    .or_print();
+            if *clean_deps_dir && deps_dir.exists() {
+                clean_dir(matches, deps_dir, |path, reporter| {
+                    remove_symlink(path, reporter)
+                })
+                .or_print(matches);
+            }
+            if *clean_git_dir && local_git_workdirs.exists() {
+                clean_dir(matches, local_git_workdirs, |path, reporter| {
+                    remove_worktree(path, *force || matches.force, reporter)
+                })
+                .or_print(matches);
+            }
+            if *clean_git_dir {
+                prune_dangling(matches, &directories).or_print(matches);
+            }
+            if *clean_deps_dir && *clean_git_dir && !local_git_workdirs.exists() {
+                // nothing is left to be detected as stale, unless a worktree was kept
+                let last_config = directories.previous_config();
+                if last_config.exists() {
+                    fs::remove_file(last_config)
+                        .context("could not remove previous config")
+                        .or_print(matches);
+                }
             }
 
-            if clean_overrides && override_file.exists() {
+            if *clean_overrides && override_file.exists() {
                 let mut to = override_file.clone();
                 to.set_file_name("overrides");
                 to.set_extension("ron.bk");
                 rename(override_file, to)
                     .context("failed to rename overrides")
-                    .or_print();
+                    .or_print(matches);
+            }
+        }
+        Some(SubCommand::Clone { dependency, target }) => {
+            let (config, _) = read_config(&matches.config)?;
+            clone_dependency(matches, &directories, &config, dependency, target)?;
+            add_local_overrides(matches, vec![(dependency.clone(), target.clone())])?;
+
+            update(matches, &directories)?;
+        }
+        Some(SubCommand::Status) => status(matches, &directories)?,
+        Some(SubCommand::Cache { command }) => cache(matches, &directories, command)?,
+    }
+
+    Ok(())
+}
+
+fn cache(opt: &Opt, directories: &Directories, command: &CacheCommand) -> Result<()> {
+    let mut reporter = |event: Event| {
+        let json = serde_json::to_value(&event).expect("events are serializable");
+        emit(opt, json, || println!("{}", event));
+    };
+
+    match command {
+        CacheCommand::List => {
+            for cached in directories.cached_repos()? {
+                let mut event =
+                    serde_json::to_value(&cached).context("could not serialize cached repo")?;
+                event["event"] = "cached_repo".into();
+                emit(opt, event, || {
+                    let last_used = match cached.last_used {
+                        Some(last_used) => {
+                            let now = SystemTime::now()
+                                .duration_since(UNIX_EPOCH)
+                                .unwrap_or_default()
+                                .as_secs();
+                            let ago = Duration::from_secs(now.saturating_sub(last_used));
+                            format!("{} ago", HumanDuration(ago))
+                        }
+                        None => "unknown".to_string(),
+                    };
+
+                    println!("{}", cached.dir.display());
+                    if let Some(url) = &cached.url {
+                        println!("  url:       {}", url);
+                    }
+                    println!("  size:      {}", HumanBytes(cached.size));
+                    println!("  last used: {}", last_used);
+                    for worktree in &cached.worktrees {
+                        println!("  worktree:  {}", worktree.display());
+                    }
+                });
+            }
+        }
+        CacheCommand::Gc { days } => {
+            directories.gc_cache(Duration::from_secs(days * 24 * 60 * 60), &mut reporter)?
+        }
+        CacheCommand::Remove { url, force } => {
+            directories.remove_cached_repo(url, *force || opt.force, &mut reporter)?
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs `remove` for every entry of `dir` and removes `dir` if that leaves it empty
+fn clean_dir(
+    opt: &Opt,
+    dir: &Path,
+    mut remove: impl FnMut(&Path, &mut JobReporter) -> Result<()>,
+) -> Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .and_then(|entries| {
+            entries
+                .map(|e| e.map(|e| e.path()))
+                .collect::<io::Result<Vec<_>>>()
+        })
+        .with_context(|| anyhow!("could not list {}", dir.display()))?;
+    entries.sort();
+
+    let bars = progress_bars(opt);
+    for path in entries {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let mut reporter = JobReporter::new(opt, &name, false, &bars);
+        remove(&path, &mut reporter)
+            .with_context(|| anyhow!("could not clean {}", path.display()))
+            .or_print(opt);
+    }
+
+    if fs::read_dir(dir)
+        .map(|mut e| e.next().is_none())
+        .unwrap_or(false)
+    {
+        fs::remove_dir(dir).with_context(|| anyhow!("could not remove {}", dir.display()))?;
+    } else {
+        let message = format!("kept {}, which is not empty", dir.display());
+        emit(
+            opt,
+            json!({ "event": "warning", "message": message }),
+            || eprintln!("warning: {}", message),
+        );
+    }
+
+    Ok(())
+}
+
+/// Removes the `links` of the dependencies in the config and in the config of the previous run
+fn remove_links(opt: &Opt, directories: &Directories) -> Result<()> {
+    let (config, _) = read_config(&opt.config)?;
+    let last_config = directories.previous_config();
+    let previous = if last_config.exists() {
+        Some(read_config(&last_config)?.0)
+    } else {
+        None
+    };
+
+    let mut links: Vec<_> = config
+        .dependencies
+        .iter()
+        .chain(previous.iter().flat_map(|c| &c.dependencies))
+        .flat_map(|(name, dependency)| dependency.links.iter().map(move |l| (name, l)))
+        .collect();
+    links.sort_by_key(|(_, link)| *link);
+    links.dedup_by_key(|(_, link)| *link);
+
+    let bars = progress_bars(opt);
+    for (name, link) in links {
+        let mut reporter = JobReporter::new(opt, name, false, &bars);
+        remove_symlink(link, &mut reporter)
+            .with_context(|| anyhow!("could not remove link {}", link.display()))
+            .or_print(opt);
+    }
+
+    Ok(())
+}
+
+/// Prunes worktrees whose directories are gone from the global repos used by the config
+fn prune_dangling(opt: &Opt, directories: &Directories) -> Result<()> {
+    let (config, _) = read_config(&opt.config)?;
+
+    let mut git_dirs: Vec<_> = config
+        .dependencies
+        .values()
+        .filter_map(|d| d.source.git_repo_url())
+        .filter_map(|url| directories.global_git_dir(url).ok())
+        .filter(|dir| dir.exists())
+        .collect();
+    git_dirs.sort();
+    git_dirs.dedup();
+
+    let bars = progress_bars(opt);
+    for git_dir in git_dirs {
+        let name = git_dir.display().to_string();
+        let mut reporter = JobReporter::new(opt, &name, false, &bars);
+        prune_dangling_worktrees(&git_dir, &mut reporter).or_print(opt);
+    }
+
+    Ok(())
+}
+
+fn find_dependency<'a>(config: &'a Config, dependency: &str) -> Result<&'a Dependency> {
+    config
+        .dependencies
+        .get(dependency)
+        .with_context(|| anyhow!("no dependency called {} in config", dependency))
+}
+
+fn clone_dependency(
+    opt: &Opt,
+    directories: &Directories,
+    config: &Config,
+    dependency: &str,
+    target: &Path,
+) -> Result<()> {
+    let dep = find_dependency(config, dependency)?;
+
+    let mut reporter = JobReporter::new(opt, dependency, false, &progress_bars(opt));
+    reporter.print(
+        json!({ "event": "cloning_dependency", "dependency": dependency, "target": target }),
+        format!(
+            "Cloning dependency {} into {}...",
+            dependency,
+            target.display()
+        ),
+    );
+    std::fs::create_dir_all(&directories.global_git_repos).unwrap();
+    dep.clone_into(directories, target, opt.offline, &mut reporter)
+        .with_context(|| anyhow!("failed to clone dependency {}", dependency))
+}
+
+/// Adds or replaces `LocalPath` overrides in the overrides file
+fn add_local_overrides(opt: &Opt, local_paths: Vec<(String, PathBuf)>) -> Result<()> {
+    let override_file = &opt.override_file();
+    let mut overrides = if override_file.exists() {
+        read_overrides(override_file)?
+    } else {
+        ConfigOverrides::default()
+    };
+    for (dependency, local_path) in local_paths {
+        overrides
+            .dependencies
+            .insert(dependency, DependencyOverride::LocalPath { local_path });
+    }
+
+    std::fs::create_dir_all(&opt.pkgstrap_dir).unwrap();
+    write_overrides(override_file, &overrides)
+}
+
+/// Creates a resolver for `config` with the overrides and lock file, if any
+fn resolver(opt: &Opt, config: Config) -> Result<Resolver> {
+    let override_file = &opt.override_file();
+    let lock_file = &opt.lock_file();
+
+    let mut resolver = Resolver::new(config).frozen(opt.frozen || opt.pedantic || opt.offline);
+
+    if override_file.exists() && !opt.no_overrides {
+        resolver = resolver.with_config_overrides(read_overrides(override_file)?);
+    }
+
+    if lock_file.exists() {
+        let lock: LockFile =
+            from_str_serde(&read_to_string(lock_file).context("could not open lock file")?)
+                .context("could not parse lock file")?;
+        resolver = resolver.with_lock_file(lock);
+    }
+
+    Ok(resolver)
+}
+
+/// Prints the state of every dependency without changing anything
+fn status(opt: &Opt, directories: &Directories) -> Result<()> {
+    let (config, _) = read_config(&opt.config)?;
+    // compare against the refs from the config rather than the locked commits
+    let resolver = resolver(opt, config.clone())?.frozen(false);
+
+    let mut names: Vec<_> = config.dependencies.keys().collect();
+    names.sort();
+    for name in names {
+        let target = config.dependencies[name]
+            .target
+            .clone()
+            .unwrap_or_else(|| directories.deps_dir.join(name));
+        let status = resolver.status(
+            name,
+            DependencyDirs {
+                base: directories,
+                std_target_dir: &target,
+                in_tree_target_dirs: vec![],
+                local_git_worktree: &directories.local_git_workdirs.join(name),
+            },
+        )?;
+
+        let yes_no = |b: Option<bool>| match b {
+            Some(true) => "yes",
+            Some(false) => "no",
+            None => "-",
+        };
+
+        let mut event = serde_json::to_value(&status).context("could not serialize status")?;
+        event["event"] = "status".into();
+        event["dependency"] = name.as_str().into();
+        event["target"] = json!(target);
+        emit(opt, event, || {
+            println!("{}", name);
+            println!("  source:  {}", status.source);
+            match &status.version {
+                Some(version) => println!(
+                    "  version: {} (matches lock: {}, matches ref: {})",
+                    version,
+                    yes_no(status.matches_lock),
+                    yes_no(status.matches_ref)
+                ),
+                None => println!("  version: not set up"),
+            }
+            if let Some((ahead, behind)) = status.ahead_behind {
+                println!("  remote:  {} ahead, {} behind", ahead, behind);
             }
+            if !status.dirty_files.is_empty() {
+                println!(
+                    "  dirty:   {} file(s)\n    {}",
+                    status.dirty_files.len(),
+                    status.dirty_files.join("\n    ")
+                );
+            }
+            match &status.link {
+                LinkStatus::Valid => println!("  link:    {} ok", target.display()),
+                LinkStatus::Missing => println!("  link:    {} missing", target.display()),
+                LinkStatus::Broken(to) => println!(
+                    "  link:    {} broken, points to {}",
+                    target.display(),
+                    to.display()
+                ),
+                LinkStatus::WrongTarget(to) => println!(
+                    "  link:    {} points to unexpected {}",
+                    target.display(),
+                    to.display()
+                ),
+                LinkStatus::NotSymlink => {
+                    println!("  link:    {} is not a symlink", target.display())
+                }
+                LinkStatus::MissingTarget(to) => println!(
+                    "  link:    {} should point to {}, which does not exist",
+                    target.display(),
+                    to.display()
+                ),
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// A dependency to be acquired, possibly on another thread
+struct Job {
+    name: String,
+    dependency: ResolvedDependency,
+    target: PathBuf,
+    links: Vec<PathBuf>,
+    local_git_worktree: PathBuf,
+    /// The global caches written by the dependency, which can't be shared between running jobs
+    cache_keys: Vec<String>,
+}
+
+impl Job {
+    /// The root of the dependency, which `target` links into if it has a `subdir`
+    fn root(&self) -> &Path {
+        match &self.dependency {
+            ResolvedDependency::GitRepository { .. } => &self.local_git_worktree,
+            ResolvedDependency::LocalPath { local_path, .. } => local_path,
+            _ => &self.target,
+        }
+    }
+
+    fn acquire(
+        &self,
+        directories: &Directories,
+        options: &AcquireOptions,
+        reporter: &mut JobReporter,
+    ) -> Result<Option<Pinned>> {
+        reporter.print(
+            json!({ "event": "setting_up", "dependency": self.name }),
+            format!("Setting up dependency {}...", self.name),
+        );
+
+        self.dependency
+            .acquire(
+                DependencyDirs {
+                    base: directories,
+                    std_target_dir: &self.target,
+                    in_tree_target_dirs: self.links.iter().map(PathBuf::as_path).collect(),
+
+                    local_git_worktree: &self.local_git_worktree,
+                },
+                options,
+                reporter,
+            )
+            .with_context(|| anyhow!("failed to acquire dependency {}", self.name))
+    }
+}
+
+/// Progress bars for git operations, which are only drawn in human mode on a terminal
+fn progress_bars(opt: &Opt) -> MultiProgress {
+    match opt.message_format {
+        MessageFormat::Human => MultiProgress::with_draw_target(ProgressDrawTarget::stderr()),
+        MessageFormat::Json => MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+    }
+}
+
+/// Prints the events of one dependency.
+///
+/// Output is buffered when running jobs in parallel, so it isn't interleaved.
+struct JobReporter {
+    dependency: String,
+    format: MessageFormat,
+    verbose: bool,
+    buffer: Option<Vec<u8>>,
+    bars: MultiProgress,
+    bar: Option<ProgressBar>,
+}
+
+impl JobReporter {
+    fn new(opt: &Opt, dependency: &str, buffered: bool, bars: &MultiProgress) -> Self {
+        JobReporter {
+            dependency: dependency.to_string(),
+            format: opt.message_format,
+            verbose: opt.verbose > 0,
+            buffer: if buffered { Some(vec![]) } else { None },
+            bars: bars.clone(),
+            bar: None,
         }
-        Some(SubCommand::Clone {
-            dependency: _,
-            target: _,
-        }) => todo!(),
     }
 
+    fn print(&mut self, event: Value, human: String) {
+        let line = match self.format {
+            MessageFormat::Json => event.to_string(),
+            MessageFormat::Human => human,
+        };
+        match &mut self.buffer {
+            Some(buffer) => {
+                buffer.extend_from_slice(line.as_bytes());
+                buffer.push(b'\n');
+            }
+            None => self.bars.suspend(|| println!("{}", line)),
+        }
+    }
+
+    fn clear_bar(&mut self) {
+        if let Some(bar) = self.bar.take() {
+            bar.finish_and_clear();
+        }
+    }
+
+    /// Prints the buffered output, if any
+    fn flush(mut self) -> Result<()> {
+        self.clear_bar();
+        if let Some(buffer) = self.buffer {
+            self.bars
+                .suspend(|| io::stdout().write_all(&buffer))
+                .context("could not print output")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Reporter for JobReporter {
+    fn report(&mut self, event: Event) {
+        if matches!(event, Event::Fetching { .. })
+            && self.format == MessageFormat::Human
+            && !self.verbose
+        {
+            return;
+        }
+
+        self.clear_bar();
+        let mut json = serde_json::to_value(&event).expect("events are serializable");
+        json["dependency"] = self.dependency.as_str().into();
+        self.print(json, format!("  {}", event));
+    }
+
+    fn progress(&mut self, progress: Progress) {
+        if self.bars.is_hidden() {
+            return;
+        }
+
+        let (message, position, length) = match progress {
+            Progress::Receiving {
+                received_objects,
+                total_objects,
+                received_bytes,
+            } => (
+                format!("receiving objects, {}", HumanBytes(received_bytes as u64)),
+                received_objects,
+                total_objects,
+            ),
+            Progress::Indexing {
+                indexed_deltas,
+                total_deltas,
+            } => ("resolving deltas".to_string(), indexed_deltas, total_deltas),
+            Progress::Checkout { completed, total } => {
+                ("checking out".to_string(), completed, total)
+            }
+        };
+
+        if length == 0 {
+            return;
+        }
+
+        let dependency = &self.dependency;
+        let bars = &self.bars;
+        let bar = self.bar.get_or_insert_with(|| {
+            let bar = bars.add(ProgressBar::new(length as u64));
+            bar.set_style(
+                ProgressStyle::with_template("{prefix:>20} [{bar:30}] {pos}/{len} {msg}")
+                    .expect("valid template")
+                    .progress_chars("=> "),
+            );
+            bar.set_prefix(dependency.clone());
+            bar
+        });
+        bar.set_length(length as u64);
+        bar.set_position(position as u64);
+        bar.set_message(message);
+    }
+}
+
+/// Removes symlinks and worktrees of dependencies that were in the config of the previous run,
+/// but aren't anymore. Failures are returned as warnings.
+fn remove_stale(opt: &Opt, directories: &Directories, resolver: &Resolver) -> Vec<Warning> {
+    let last_config = directories.previous_config();
+    if !last_config.exists() {
+        return vec![];
+    }
+    let previous = match read_config(&last_config) {
+        Ok((previous, _)) => previous,
+        Err(e) => {
+            return vec![Warning::InvalidPreviousConfig {
+                error: e.to_string(),
+            }]
+        }
+    };
+
+    let mut warnings = vec![];
+    for stale in resolver.stale_dependencies(&previous, &directories.deps_dir) {
+        let name = &stale.name;
+        let mut reporter = JobReporter::new(opt, name, false, &progress_bars(opt));
+        reporter.print(
+            json!({ "event": "removing_stale", "dependency": name }),
+            format!("Removing stale dependency {}...", name),
+        );
+        let mut result = Ok(());
+        for link in stale.target.iter().chain(&stale.links) {
+            result = result.and_then(|_| remove_symlink(link, &mut reporter));
+        }
+        if stale.removed {
+            let worktree = directories.local_git_workdirs.join(name);
+            result = result.and_then(|_| remove_worktree(&worktree, opt.force, &mut reporter));
+        }
+
+        if let Err(e) = result {
+            warnings.push(Warning::StaleNotRemoved {
+                dependency: name.clone(),
+                error: format!("{:#}", e),
+            });
+        }
+    }
+
+    warnings
+}
+
+/// Prints warnings, which are errors in pedantic mode
+fn report_warnings(opt: &Opt, warnings: &[Warning]) -> Result<()> {
+    if opt.pedantic && !warnings.is_empty() {
+        for warning in warnings {
+            emit(
+                opt,
+                json!({ "event": "error", "message": warning.to_string(), "causes": [] }),
+                || eprintln!("error: {}", warning),
+            );
+        }
+        bail!("{} problem(s) not allowed in pedantic mode", warnings.len());
+    }
+    for warning in warnings {
+        let lock_related = matches!(
+            warning,
+            Warning::MissingLockEntry { .. } | Warning::UnpinnedBranch { .. }
+        );
+        if opt.frozen || !lock_related {
+            emit(
+                opt,
+                json!({ "event": "warning", "message": warning.to_string() }),
+                || eprintln!("warning: {}", warning),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Initializes / updates all dependencies
+fn update(opt: &Opt, directories: &Directories) -> Result<()> {
+    let Directories {
+        pkgstrap_dir,
+        deps_dir,
+        local_git_workdirs,
+        global_git_repos,
+        ..
+    } = directories;
+    let lock_file = &opt.lock_file();
+
+    let (config, config_contents) = read_config(&opt.config)?;
+
+    let mut resolver = resolver(opt, config.clone())?;
+
+    std::fs::create_dir_all(pkgstrap_dir).unwrap();
+    std::fs::create_dir_all(deps_dir).unwrap();
+    std::fs::create_dir_all(global_git_repos).unwrap();
+    std::fs::create_dir_all(local_git_workdirs).unwrap();
+
+    let project_root = std::env::current_dir().context("invalid working directory")?;
+    let warnings = resolver.warnings(&project_root);
+    report_warnings(opt, &warnings)?;
+
+    let options = AcquireOptions {
+        on_dirty: if opt.force {
+            DirtyWorktree::Force
+        } else if opt.stash {
+            DirtyWorktree::Stash
+        } else {
+            DirtyWorktree::Abort
+        },
+        pedantic: opt.pedantic,
+        offline: opt.offline,
+    };
+    let new_job = |resolver: &Resolver, name: String| -> Result<Job> {
+        let dependency = resolver.resolve(&name)?;
+        let configured = config.dependencies.get(&name);
+        let target = configured
+            .and_then(|d| d.target.clone())
+            .unwrap_or_else(|| deps_dir.join(&name));
+
+        Ok(Job {
+            cache_keys: dependency.cache_keys(directories)?,
+            local_git_worktree: local_git_workdirs.join(&name),
+            name,
+            dependency,
+            target,
+            links: configured.map(|d| d.links.clone()).unwrap_or_default(),
+        })
+    };
+
+    let mut names: Vec<_> = config.dependencies.keys().cloned().collect();
+    names.sort();
+    let mut pending = names
+        .into_iter()
+        .map(|name| new_job(&resolver, name))
+        .collect::<Result<VecDeque<_>>>()?;
+    let mut pins = HashMap::new();
+
+    let jobs = opt.jobs.max(1);
+    let bars = progress_bars(opt);
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| -> Result<()> {
+        let mut busy_keys = HashSet::new();
+        let mut running = 0;
+        let mut error = None;
+        // offline, every dependency that can be set up from the cache is
+        let mut missing = vec![];
+
+        loop {
+            while running < jobs && error.is_none() {
+                // dependencies sharing a bare repo or download have to wait for each other
+                let next = pending
+                    .iter()
+                    .position(|j| j.cache_keys.iter().all(|k| !busy_keys.contains(k)));
+                let job = match next.and_then(|i| pending.remove(i)) {
+                    Some(job) => job,
+                    None => break,
+                };
+                busy_keys.extend(job.cache_keys.iter().cloned());
+
+                let sender = sender.clone();
+                let options = &options;
+                let mut reporter = JobReporter::new(opt, &job.name, jobs > 1, &bars);
+                scope.spawn(move || {
+                    // the scheduler waits for every job, so a panic must not go unreported
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        job.acquire(directories, options, &mut reporter)
+                    }))
+                    .unwrap_or_else(|_| Err(anyhow!("acquiring {} panicked", job.name)));
+                    sender
+                        .send((job, result, reporter))
+                        .expect("receiver outlives jobs");
+                });
+                running += 1;
+            }
+            if running == 0 {
+                break;
+            }
+
+            let (job, result, reporter) = receiver.recv().expect("senders outlive running jobs");
+            running -= 1;
+            reporter.flush()?;
+            for key in &job.cache_keys {
+                busy_keys.remove(key);
+            }
+
+            let result = result.and_then(|pinned| {
+                if let Some(pinned) = pinned {
+                    pins.insert(job.name.clone(), pinned);
+                }
+
+                let nested_config = job.root().join("pkgstrap.ron");
+                if nested_config.is_file() {
+                    let (nested, _) = read_config(&nested_config)
+                        .with_context(|| anyhow!("invalid config of dependency {}", job.name))?;
+                    let added = resolver.add_nested_config(&job.name, nested)?;
+                    if opt.verbose > 0 && !added.is_empty() {
+                        emit(
+                            opt,
+                            json!({ "event": "requires", "dependency": job.name, "dependencies": added }),
+                            || println!("  {} requires {}", job.name, added.join(", ")),
+                        );
+                    }
+                    for name in added {
+                        pending.push_back(new_job(&resolver, name)?);
+                    }
+                }
+
+                Ok(())
+            });
+            match result {
+                Err(e) if opt.offline => {
+                    print_err(opt, e);
+                    missing.push(job.name);
+                }
+                // let running jobs finish, but don't start new ones
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+                Ok(()) => {}
+            }
+        }
+
+        if let Some(e) = error {
+            return Err(e);
+        }
+        if !missing.is_empty() {
+            missing.sort();
+            bail!(
+                "could not set up {} offline: {}",
+                if missing.len() == 1 {
+                    "dependency"
+                } else {
+                    "dependencies"
+                },
+                missing.join(", ")
+            );
+        }
+
+        Ok(())
+    })?;
+
+    // warnings about transitive dependencies are only known after acquiring their parents
+    let transitive_warnings: Vec<_> = resolver
+        .warnings(&project_root)
+        .into_iter()
+        .filter(|w| !warnings.contains(w))
+        .collect();
+    report_warnings(opt, &transitive_warnings)?;
+
+    if !opt.keep_stale {
+        report_warnings(opt, &remove_stale(opt, directories, &resolver))?;
+    }
+
+    let lock = to_pretty_string_serde(&resolver.lock_file(&pins), Default::default())
+        .context("could not serialize lock file")?;
+    fs::write(lock_file, lock).context("could not write lock file")?;
+    if opt.verbose > 0 {
+        emit(
+            opt,
+            json!({ "event": "lock_written", "path": lock_file }),
+            || println!("Wrote {}", lock_file.display()),
+        );
+    }
+
+    directories.record_config(&config_contents, opt.keep_stale)?;
+
     Ok(())
 }